@@ -0,0 +1,522 @@
+//! Procedural macros for `tb2f_commandspec`. Use them through the
+//! re-exports in that crate.
+
+extern crate proc_macro;
+
+mod template;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use template::{Piece, Word};
+
+/// Builds the `Command` for `checked_command!` from a template parsed while
+/// compiling.
+///
+/// Placeholders work as in `command!`. Malformed preambles, unbalanced quotes
+/// and unknown or unused arguments are reported as compiler errors. The expansion builds the `Command` directly
+/// from the parsed words, so values are never quoted and split again.
+///
+/// Like the other macros here, it takes an optional `crate = path,` before
+/// the template, which is where the generated code finds `tb2f_commandspec`.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __checked_command(input: TokenStream) -> TokenStream {
+    match expand_checked_command(input) {
+        Ok(tokens) => tokens,
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+/// Formats a template for the `macro_rules!` macros such as `command!`.
+///
+/// Takes the same arguments as `format!`: positional values, `name = value`
+/// pairs, and names captured from the surrounding scope. Every value goes
+/// through `command_arg`, so it is quoted the same way as a named argument.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __command_format(input: TokenStream) -> TokenStream {
+    match expand_command_format(input) {
+        Ok(tokens) => tokens,
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+/// Builds a `Template` for the `macro_rules!` macros such as `pipeline!`.
+///
+/// Takes the same arguments as `__command_format!`, but keeps every value
+/// apart from the literal text instead of formatting it into the string.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __command_template(input: TokenStream) -> TokenStream {
+    match expand_command_template(input, false) {
+        Ok(tokens) => tokens,
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+/// Like `__command_template!`, for `command!`. The template is checked the
+/// way `commandify` will check it, so mistakes that don't depend on the
+/// values are compiler errors.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __checked_template(input: TokenStream) -> TokenStream {
+    match expand_command_template(input, true) {
+        Ok(tokens) => tokens,
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+type Error = (Span, String);
+
+struct Argument {
+    /// The name for `name = value` and captured arguments; positional ones have none.
+    name: Option<Ident>,
+    span: Span,
+    value: TokenStream,
+    used: bool,
+}
+
+// The arguments of a macro call, with every placeholder of the template
+// resolved to one of them.
+struct Arguments {
+    list: Vec<Argument>,
+    /// How many arguments were passed; captured ones come after these.
+    explicit: usize,
+    span: Span,
+}
+
+impl Arguments {
+    fn parse(parts: Vec<TokenStream>, span: Span) -> Result<Arguments, Error> {
+        let mut list: Vec<Argument> = vec![];
+        for part in parts {
+            let argument = argument(part)?;
+            match argument.name {
+                Some(ref name) => {
+                    if list.iter().any(|other| other.name.as_ref().map(Ident::to_string) == Some(name.to_string())) {
+                        return Err((name.span(), format!("duplicate argument {}", name)));
+                    }
+                }
+                None => {
+                    if list.iter().any(|other| other.name.is_some()) {
+                        return Err((argument.span, "positional arguments must come before named ones".into()));
+                    }
+                }
+            }
+            list.push(argument);
+        }
+        Ok(Arguments {
+            explicit: list.len(),
+            list,
+            span,
+        })
+    }
+
+    /// Returns the index of the argument for a placeholder, capturing a
+    /// variable from the caller's scope if there is no such argument. Like
+    /// in `format!`, an index counts named arguments too.
+    fn resolve(&mut self, key: &str) -> Result<usize, Error> {
+        let found = match key.parse::<usize>() {
+            Ok(index) => Some(index).filter(|&index| index < self.explicit),
+            Err(_) => self.list.iter().position(|argument| argument.name.as_ref().is_some_and(|name| name.to_string() == key)),
+        };
+        let index = match found {
+            Some(index) => index,
+            None if template::is_identifier(key) => {
+                // The span of the template makes the name resolve where the macro was called.
+                let name = Ident::new(key, self.span);
+                self.list.push(Argument {
+                    name: Some(name.clone()),
+                    span: self.span,
+                    value: Some(TokenTree::Ident(name)).into_iter().collect(),
+                    used: false,
+                });
+                self.list.len() - 1
+            }
+            None => return Err((self.span, format!("invalid reference to positional argument {}", key))),
+        };
+        self.list[index].used = true;
+        Ok(index)
+    }
+
+    // Evaluates every argument once, in order, through `command_arg`.
+    fn bindings(self) -> Result<TokenStream, Error> {
+        if let Some(argument) = self.list.iter().find(|argument| !argument.used) {
+            let message = match argument.name {
+                Some(ref name) => format!("argument `{}` is never used", name),
+                None => "argument never used".into(),
+            };
+            return Err((argument.span, message));
+        }
+
+        let mut output = TokenStream::new();
+        for (index, argument) in self.list.into_iter().enumerate() {
+            let mut value = parse("&");
+            value.extend(Some(TokenTree::Group(Group::new(Delimiter::Parenthesis, argument.value))));
+            output.extend(parse(&format!(
+                "let __commandspec_arg{} = __commandspec_crate::command_arg",
+                index
+            )));
+            output.extend(Some(TokenTree::Group(Group::new(Delimiter::Parenthesis, value))));
+            output.extend(parse(";"));
+        }
+        Ok(output)
+    }
+}
+
+fn expand_command_format(input: TokenStream) -> Result<TokenStream, Error> {
+    let (krate, template, parts) = split_input(input)?;
+    let (template, span) = string_literal(template)?;
+    let mut arguments = Arguments::parse(parts, span)?;
+
+    let segments = template::segments(&template).map_err(|message| (span, message))?;
+    let mut format = String::new();
+    for piece in segments {
+        match piece {
+            Piece::Text(text) => format.push_str(&text.replace('{', "{{").replace('}', "}}")),
+            Piece::Arg(key) => format.push_str(&format!("{{__commandspec_arg{}}}", arguments.resolve(&key)?)),
+        }
+    }
+
+    let mut output = arguments.bindings()?;
+    output.extend(parse(&format!("::std::format!({})", Literal::string(&format))));
+    Ok(block(output, &krate))
+}
+
+fn expand_command_template(input: TokenStream, check: bool) -> Result<TokenStream, Error> {
+    let (krate, template, parts) = split_input(input)?;
+    let (template, span) = string_literal(template)?;
+    let mut arguments = Arguments::parse(parts, span)?;
+
+    let segments = template::segments(&template).map_err(|message| (span, message))?;
+    if check {
+        template::check(segments.clone()).map_err(|message| (span, message))?;
+    }
+    let mut pieces = vec![];
+    for piece in segments {
+        pieces.push(match piece {
+            Piece::Text(text) => (Some(text), None),
+            Piece::Arg(key) => (None, Some(arguments.resolve(&key)?)),
+        });
+    }
+
+    let mut body = String::from("__commandspec_crate::Template::new()");
+    for (position, piece) in pieces.iter().enumerate() {
+        match *piece {
+            (Some(ref text), _) => body.push_str(&format!(".text({})", Literal::string(text))),
+            // A value used again later is cloned; its last use moves it.
+            (_, Some(index)) if pieces[position + 1..].iter().any(|other| other.1 == Some(index)) => {
+                body.push_str(&format!(".arg(__commandspec_arg{}.clone())", index));
+            }
+            (_, index) => body.push_str(&format!(".arg(__commandspec_arg{})", index.unwrap())),
+        }
+    }
+
+    let mut output = arguments.bindings()?;
+    output.extend(parse(&body));
+    Ok(block(output, &krate))
+}
+
+fn expand_checked_command(input: TokenStream) -> Result<TokenStream, Error> {
+    let (krate, template, parts) = split_input(input)?;
+    let (template, span) = string_literal(template)?;
+    let mut arguments = Arguments::parse(parts, span)?;
+
+    let spec = template::segments(&template)
+        .and_then(template::parse)
+        .map_err(|message| (span, message))?;
+
+    // Literal words become builder calls as they are. Words holding values
+    // are put together at runtime, since a value may add any number of words.
+    let mut words = |list: &[Word]| -> Result<String, Error> {
+        let mut body = String::from("{ let mut __commandspec_words = __commandspec_crate::argv::Argv::new();");
+        for word in list {
+            for piece in word {
+                match *piece {
+                    Piece::Text(ref text) => {
+                        body.push_str(&format!("__commandspec_words.text({});", Literal::string(text)));
+                    }
+                    Piece::Arg(ref key) => {
+                        let index = arguments.resolve(key)?;
+                        body.push_str(&format!("__commandspec_words.arg(&__commandspec_arg{});", index));
+                    }
+                }
+            }
+            body.push_str("__commandspec_words.end_word();");
+        }
+        body.push_str("__commandspec_words.into_words() }");
+        Ok(body)
+    };
+    let literal = |word: &Word| match word[..] {
+        [Piece::Text(ref text)] => Some(text.clone()),
+        _ => None,
+    };
+    // Fallible steps leave the labeled block early with their error.
+    let fallible = |call: String| {
+        format!(
+            "match {} {{ Ok(value) => value, Err(err) => break '__commandspec Err(err) }}",
+            call
+        )
+    };
+
+    let mut body = String::new();
+    let mut argv = &spec.argv[..];
+    match literal(&argv[0]) {
+        Some(program) => {
+            body.push_str(&format!(
+                "let mut __commandspec_command = ::std::process::Command::new({});",
+                Literal::string(&program)
+            ));
+            argv = &argv[1..];
+        }
+        None => {
+            let all = words(argv)?;
+            body.push_str(&format!(
+                "let mut __commandspec_command = {};",
+                fallible(format!("__commandspec_crate::argv::command({})", all))
+            ));
+            argv = &[];
+        }
+    }
+    for word in argv {
+        match literal(word) {
+            Some(text) => body.push_str(&format!("__commandspec_command.arg({});", Literal::string(&text))),
+            None => body.push_str(&format!("__commandspec_command.args({});", words(::std::slice::from_ref(word))?)),
+        }
+    }
+    for word in &spec.env {
+        match literal(word).as_ref().and_then(|text| text.split_once('=')) {
+            Some((key, value)) => body.push_str(&format!(
+                "__commandspec_command.env({}, {});",
+                Literal::string(key),
+                Literal::string(value)
+            )),
+            None => body.push_str(&format!(
+                "__commandspec_command.envs({});",
+                fallible(format!("__commandspec_crate::argv::exports({})", words(::std::slice::from_ref(word))?))
+            )),
+        }
+    }
+    if let Some(ref cd) = spec.cd {
+        body.push_str(&format!(
+            "__commandspec_command.current_dir({});",
+            fallible(format!("__commandspec_crate::argv::current_dir({})", words(::std::slice::from_ref(cd))?))
+        ));
+    }
+    body.push_str("::std::result::Result::Ok::<_, __commandspec_crate::CommandError>(__commandspec_command)");
+    let body = if body.contains("'__commandspec") {
+        format!("'__commandspec: {{ {} }}", body)
+    } else {
+        body
+    };
+
+    let mut output = arguments.bindings()?;
+    output.extend(parse(&body));
+    Ok(block(output, &krate))
+}
+
+fn parse(source: &str) -> TokenStream {
+    source.parse().expect("generated code should parse")
+}
+
+// Wraps generated code in a block, with `__commandspec_crate` replaced by the
+// path of the crate. `$crate` can't be written into the source text, so it
+// is put in afterwards.
+fn block(output: TokenStream, krate: &TokenStream) -> TokenStream {
+    fn replace(stream: TokenStream, krate: &TokenStream) -> TokenStream {
+        stream
+            .into_iter()
+            .flat_map(|tree| match tree {
+                TokenTree::Ident(ref ident) if ident.to_string() == "__commandspec_crate" => krate.clone(),
+                TokenTree::Group(group) => {
+                    let mut replaced = Group::new(group.delimiter(), replace(group.stream(), krate));
+                    replaced.set_span(group.span());
+                    Some(TokenTree::Group(replaced)).into_iter().collect()
+                }
+                tree => Some(tree).into_iter().collect(),
+            })
+            .collect()
+    }
+    Some(TokenTree::Group(Group::new(Delimiter::Brace, replace(output, krate)))).into_iter().collect()
+}
+
+// Splits macro input into the crate path, the template and the arguments.
+// The `macro_rules!` wrappers pass `crate = $crate` first, and each argument
+// as an `expr` fragment, so commas inside an argument are never split on.
+fn split_input(input: TokenStream) -> Result<(TokenStream, TokenStream, Vec<TokenStream>), Error> {
+    let mut parts = split_commas(input)?.into_iter().peekable();
+    let krate = match parts.peek().map(|part| part.clone().into_iter().collect::<Vec<_>>()) {
+        Some(ref trees) if trees.len() > 2 && is_crate_key(&trees[0], &trees[1]) => {
+            parts.next();
+            trees[2..].iter().cloned().collect()
+        }
+        _ => parse("::tb2f_commandspec"),
+    };
+    match parts.next() {
+        Some(template) => Ok((krate, template, parts.collect())),
+        None => Err((Span::call_site(), "expected a template string".into())),
+    }
+}
+
+fn is_crate_key(key: &TokenTree, eq: &TokenTree) -> bool {
+    match (key, eq) {
+        (TokenTree::Ident(key), TokenTree::Punct(eq)) => key.to_string() == "crate" && eq.as_char() == '=',
+        _ => false,
+    }
+}
+
+// Splits macro input at top-level commas. Only a trailing comma may be
+// left without an argument before it.
+fn split_commas(input: TokenStream) -> Result<Vec<TokenStream>, Error> {
+    let mut parts = vec![];
+    let mut current = TokenStream::new();
+    for tree in input {
+        match tree {
+            TokenTree::Punct(ref punct) if punct.as_char() == ',' => {
+                if current.is_empty() {
+                    return Err((punct.span(), "expected an argument before this comma".into()));
+                }
+                parts.push(::std::mem::replace(&mut current, TokenStream::new()));
+            }
+            tree => current.extend(Some(tree)),
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    Ok(parts)
+}
+
+// Parses `name = value`, or a positional `value`.
+fn argument(part: TokenStream) -> Result<Argument, Error> {
+    let trees = without_invisible_groups(part);
+    let span = trees[0].span();
+    let named = match (trees.first(), trees.get(1), trees.get(2)) {
+        (Some(TokenTree::Ident(_)), Some(TokenTree::Punct(eq)), next) if eq.as_char() == '=' => match next {
+            // Not `a == b`.
+            Some(TokenTree::Punct(other)) => eq.spacing() == Spacing::Alone || other.as_char() != '=',
+            _ => true,
+        },
+        _ => false,
+    };
+    if !named {
+        return Ok(Argument {
+            name: None,
+            span,
+            value: trees.into_iter().collect(),
+            used: false,
+        });
+    }
+
+    let mut trees = trees.into_iter();
+    let name = match trees.next() {
+        Some(TokenTree::Ident(name)) => name,
+        _ => unreachable!(),
+    };
+    trees.next();
+    let value: TokenStream = trees.collect();
+    if value.is_empty() {
+        return Err((name.span(), format!("expected a value for argument `{}`", name)));
+    }
+    Ok(Argument {
+        name: Some(name),
+        span,
+        value,
+        used: false,
+    })
+}
+
+// `$value:expr` fragments from macro_rules arrive wrapped in an invisible group.
+fn without_invisible_groups(part: TokenStream) -> Vec<TokenTree> {
+    let mut trees = part.into_iter().collect::<Vec<_>>();
+    while trees.len() == 1 {
+        match trees[0] {
+            TokenTree::Group(ref group) if group.delimiter() == Delimiter::None => {}
+            _ => break,
+        }
+        trees = match trees.remove(0) {
+            TokenTree::Group(group) => group.stream().into_iter().collect(),
+            _ => unreachable!(),
+        };
+    }
+    trees
+}
+
+fn string_literal(part: TokenStream) -> Result<(String, Span), Error> {
+    let trees = without_invisible_groups(part);
+    match (trees.len(), trees.first()) {
+        (1, Some(TokenTree::Literal(literal))) => match unquote(&literal.to_string()) {
+            Some(value) => Ok((value, literal.span())),
+            None => Err((literal.span(), "expected a string literal".into())),
+        },
+        (_, Some(tree)) => Err((tree.span(), "expected a string literal".into())),
+        (_, None) => Err((Span::call_site(), "expected a string literal".into())),
+    }
+}
+
+// Decodes the source text of a string literal.
+fn unquote(source: &str) -> Option<String> {
+    if let Some(raw) = source.strip_prefix('r') {
+        let hashes = raw.chars().take_while(|&ch| ch == '#').count();
+        let inner = &raw[hashes..raw.len() - hashes];
+        return Some(inner[1..inner.len() - 1].to_string());
+    }
+    if !source.starts_with('"') || !source.ends_with('"') || source.len() < 2 {
+        return None;
+    }
+
+    let mut result = String::new();
+    let mut chars = source[1..source.len() - 1].chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next()? {
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            't' => result.push('\t'),
+            '0' => result.push('\0'),
+            '\\' => result.push('\\'),
+            '\'' => result.push('\''),
+            '"' => result.push('"'),
+            'x' => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                result.push(u8::from_str_radix(&hex, 16).ok()? as char);
+            }
+            'u' => {
+                let hex = chars
+                    .by_ref()
+                    .skip(1)
+                    .take_while(|&ch| ch != '}')
+                    .collect::<String>();
+                result.push(::std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            '\n' => {
+                while let Some(&ch) = chars.peek() {
+                    if !ch.is_whitespace() {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, Some(TokenTree::Literal(literal)).into_iter().collect());
+    group.set_span(span);
+    vec![
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(group),
+    ]
+    .into_iter()
+    .collect()
+}
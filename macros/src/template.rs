@@ -0,0 +1,688 @@
+// Parses a command spec template at compile time.
+//
+// This mirrors what `commandify` does at runtime: the same `cd`/`export`
+// preamble rules and the same quoting as `shlex::split`. The difference is
+// that placeholders are kept apart from the literal text, so their values can
+// become arguments as they are instead of being quoted and split again.
+
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Piece {
+    Text(String),
+    Arg(String),
+}
+
+/// One argument, built from literal text and interpolated values.
+pub type Word = Vec<Piece>;
+
+#[derive(Debug, PartialEq)]
+pub struct Spec {
+    pub cd: Option<Word>,
+    pub env: Vec<Word>,
+    pub argv: Vec<Word>,
+}
+
+/// Splits a template into literal text and placeholders. A placeholder is
+/// kept as its argument name, or as its index for `{}` and `{0}`.
+pub fn segments(template: &str) -> Result<Vec<Piece>, String> {
+    let mut result = vec![];
+    let mut text = String::new();
+    let mut next_position = 0;
+    let mut chars = template.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(ch) => name.push(ch),
+                        None => return Err("unclosed { in template; use {{ for a literal {".into()),
+                    }
+                }
+                let mut name = name.trim().to_string();
+                if name.contains(':') {
+                    return Err(format!("format specs aren't supported in {{{}}}; values are always quoted as arguments", name));
+                }
+                if name.is_empty() {
+                    name = next_position.to_string();
+                    next_position += 1;
+                } else if !is_identifier(&name) && !is_index(&name) {
+                    return Err(format!("expected a placeholder such as {{}}, {{0}} or {{name}}, found {{{}}}", name));
+                }
+                if !text.is_empty() {
+                    result.push(Piece::Text(text));
+                    text = String::new();
+                }
+                result.push(Piece::Arg(name));
+            }
+            '}' => return Err("unmatched } in template; use }} for a literal }".into()),
+            _ => text.push(ch),
+        }
+    }
+    if !text.is_empty() {
+        result.push(Piece::Text(text));
+    }
+
+    Ok(result)
+}
+
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(ch) if ch == '_' || ch.is_alphabetic() => chars.all(|ch| ch == '_' || ch.is_alphanumeric()),
+        _ => false,
+    }
+}
+
+fn is_index(name: &str) -> bool {
+    name.chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Parses the segments of a template into its preamble and command.
+pub fn parse(mut segments: Vec<Piece>) -> Result<Spec, String> {
+    // Like `value.trim()` in `commandify`.
+    if let Some(Piece::Text(text)) = segments.first_mut() {
+        *text = text.trim_start().to_string();
+    }
+    if let Some(Piece::Text(text)) = segments.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+
+    let mut spec = Spec {
+        cd: None,
+        env: vec![],
+        argv: vec![],
+    };
+    let mut seen_export = false;
+    let mut lines = split_lines(segments).into_iter();
+    let mut command_lines = vec![];
+
+    for line in lines.by_ref() {
+        let words = match self::words(line.clone()) {
+            Ok(words) => words,
+            Err(_) => {
+                command_lines.push(line);
+                break;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+        if words[0] == [Piece::Text("cd".into())] {
+            if spec.cd.is_some() || seen_export {
+                return Err("cd should be the first line in your command! macro.".into());
+            }
+            if words.len() != 2 {
+                return Err(format!("Too many arguments in cd; expected 1, found {}", words.len() - 1));
+            }
+            spec.cd = words.into_iter().nth(1);
+        } else if words[0] == [Piece::Text("export".into())] {
+            if words.len() < 2 {
+                return Err("Not enough arguments in export; expected at least 1, found 0".into());
+            }
+            for word in &words[1..] {
+                // Only a name made of literal text can be checked here.
+                if let Some(Piece::Text(text)) = word.first() {
+                    if !text.contains('=') && word.len() == 1 {
+                        return Err("Expected export of the format NAME=VALUE".into());
+                    }
+                }
+            }
+            spec.env.extend(words.into_iter().skip(1));
+            seen_export = true;
+        } else if words[0] == [Piece::Text("timeout".into())] && words.len() == 2 {
+            return Err(timeout_preamble());
+        } else {
+            command_lines.push(line);
+            break;
+        }
+    }
+    command_lines.extend(lines);
+
+    let mut command = vec![];
+    for (index, line) in command_lines.into_iter().enumerate() {
+        if index > 0 {
+            command.push(Piece::Text("\n".into()));
+        }
+        command.extend(line);
+    }
+    let command = merge_text(command)
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => Piece::Text(text.replace("\\\n", "\n")),
+            arg => arg,
+        })
+        .collect();
+
+    spec.argv = words(command)?;
+    if spec.argv.is_empty() {
+        return Err("Didn't find a command in your command! macro.".into());
+    }
+
+    Ok(spec)
+}
+
+fn split_lines(segments: Vec<Piece>) -> Vec<Vec<Piece>> {
+    let mut lines = vec![vec![]];
+    for piece in segments {
+        match piece {
+            Piece::Text(text) => {
+                for (index, part) in text.split('\n').enumerate() {
+                    if index > 0 {
+                        lines.push(vec![]);
+                    }
+                    if !part.is_empty() {
+                        lines.last_mut().unwrap().push(Piece::Text(part.to_string()));
+                    }
+                }
+            }
+            arg => lines.last_mut().unwrap().push(arg),
+        }
+    }
+    lines
+}
+
+fn merge_text(pieces: Vec<Piece>) -> Vec<Piece> {
+    let mut result: Vec<Piece> = vec![];
+    for piece in pieces {
+        match (result.last_mut(), piece) {
+            (Some(Piece::Text(last)), Piece::Text(text)) => last.push_str(&text),
+            (_, piece) => result.push(piece),
+        }
+    }
+    result
+}
+
+enum Item {
+    Char(char),
+    Arg(String),
+}
+
+type Items = Peekable<IntoIter<Item>>;
+
+/// A word or an operator of the command section.
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Word(Word),
+    /// `|`, `||`, `&&` or `;`, as written.
+    Connector(&'static str),
+    /// `<`, `>`, `>>`, `2>` and the like, which need a file name after them.
+    Redirect,
+    /// `>&1` or `2>&1`.
+    Duplicate,
+    /// A `<<` redirect; its body was skipped.
+    Heredoc,
+}
+
+struct Words {
+    tokens: Vec<Token>,
+    current: Option<Word>,
+    // Whether the current word used quotes, escapes or values, so `"2">` isn't taken for `2>`.
+    quoted: bool,
+}
+
+impl Words {
+    fn push_char(&mut self, ch: char) {
+        let word = self.current.get_or_insert_with(Vec::new);
+        if let Some(Piece::Text(text)) = word.last_mut() {
+            text.push(ch);
+            return;
+        }
+        word.push(Piece::Text(ch.to_string()));
+    }
+
+    fn push_arg(&mut self, name: String) {
+        self.quoted = true;
+        self.current.get_or_insert_with(Vec::new).push(Piece::Arg(name));
+    }
+
+    // Quotes start a word even if nothing ends up inside them.
+    fn start(&mut self) {
+        self.quoted = true;
+        self.current.get_or_insert_with(Vec::new);
+    }
+
+    fn flush(&mut self) {
+        if let Some(word) = self.current.take() {
+            self.tokens.push(Token::Word(word));
+        }
+        self.quoted = false;
+    }
+}
+
+/// Splits into words with the quoting rules of `shlex::split`. Placeholders
+/// become part of the word they appear in, inside quotes or not.
+///
+/// Operators are recognized the way the runtime lexer does, and the body of
+/// a heredoc is skipped up to its closing line.
+pub fn tokenize(segments: Vec<Piece>) -> Result<Vec<Token>, String> {
+    let mut items = vec![];
+    for piece in segments {
+        match piece {
+            Piece::Text(text) => items.extend(text.chars().map(Item::Char)),
+            Piece::Arg(name) => items.push(Item::Arg(name)),
+        }
+    }
+    let mut items = items.into_iter().peekable();
+    let mut words = Words {
+        tokens: vec![],
+        current: None,
+        quoted: false,
+    };
+    // Heredocs whose body starts after the current line.
+    let mut heredocs: Vec<String> = vec![];
+
+    while let Some(item) = items.next() {
+        let ch = match item {
+            Item::Arg(name) => {
+                words.push_arg(name);
+                continue;
+            }
+            Item::Char(ch) => ch,
+        };
+        match ch {
+            ' ' | '\t' => words.flush(),
+            '\n' => {
+                words.flush();
+                for delimiter in heredocs.drain(..) {
+                    skip_heredoc(&mut items, &delimiter)?;
+                }
+            }
+            // The newline ending a comment is left for the case above.
+            '#' if words.current.is_none() => {
+                while items.peek().is_some() && !is_char(items.peek(), '\n') {
+                    items.next();
+                }
+            }
+            '|' => {
+                words.flush();
+                let connector = if is_char(items.peek(), '|') {
+                    items.next();
+                    "||"
+                } else {
+                    "|"
+                };
+                words.tokens.push(Token::Connector(connector));
+            }
+            '&' if is_char(items.peek(), '&') => {
+                items.next();
+                words.flush();
+                words.tokens.push(Token::Connector("&&"));
+            }
+            ';' => {
+                words.flush();
+                words.tokens.push(Token::Connector(";"));
+            }
+            '<' => {
+                words.flush();
+                if is_char(items.peek(), '<') {
+                    items.next();
+                    heredocs.push(read_delimiter(&mut items).ok_or_else(invalid_heredoc)?);
+                    words.tokens.push(Token::Heredoc);
+                } else {
+                    words.tokens.push(Token::Redirect);
+                }
+            }
+            '>' => {
+                // A bare 1 or 2 right before > selects the stream, like in a shell.
+                let stream = [[Piece::Text("1".into())], [Piece::Text("2".into())]];
+                if !words.quoted && words.current.as_ref().is_some_and(|word| stream.iter().any(|fd| fd[..] == word[..])) {
+                    words.current = None;
+                }
+                words.flush();
+                if is_char(items.peek(), '>') {
+                    items.next();
+                    words.tokens.push(Token::Redirect);
+                } else if is_char(items.peek(), '&') {
+                    items.next();
+                    if !is_char(items.peek(), '1') && !is_char(items.peek(), '2') {
+                        return Err("Redirections need a file name, or 1 or 2 after >&, in your command! macro.".into());
+                    }
+                    items.next();
+                    words.tokens.push(Token::Duplicate);
+                } else {
+                    words.tokens.push(Token::Redirect);
+                }
+            }
+            '\\' => {
+                words.quoted = true;
+                match items.next() {
+                    Some(Item::Char('\n')) => {}
+                    Some(Item::Char(ch2)) => words.push_char(ch2),
+                    Some(Item::Arg(name)) => {
+                        words.push_char('\\');
+                        words.push_arg(name);
+                    }
+                    None => return Err(unbalanced()),
+                }
+            }
+            '\'' => {
+                words.start();
+                parse_quoted(&mut items, &mut words, '\'')?;
+            }
+            '"' => {
+                words.start();
+                parse_quoted(&mut items, &mut words, '"')?;
+            }
+            _ => words.push_char(ch),
+        }
+    }
+    words.flush();
+    if !heredocs.is_empty() {
+        return Err(invalid_heredoc());
+    }
+
+    Ok(words.tokens)
+}
+
+/// Like `tokenize`, but only for a single command without operators.
+pub fn words(segments: Vec<Piece>) -> Result<Vec<Word>, String> {
+    let mut words = vec![];
+    for token in tokenize(segments)? {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Connector(connector) => return Err(unsupported(connector)),
+            Token::Redirect | Token::Duplicate => return Err(unsupported(">")),
+            Token::Heredoc => return Err(unsupported("<<")),
+        }
+    }
+    Ok(words)
+}
+
+// Reads the word after `<<`, dropping quotes around it.
+fn read_delimiter(items: &mut Items) -> Option<String> {
+    while is_char(items.peek(), ' ') || is_char(items.peek(), '\t') {
+        items.next();
+    }
+    let mut delimiter = String::new();
+    while let Some(&Item::Char(ch)) = items.peek() {
+        if ch.is_whitespace() || "|&;<>".contains(ch) {
+            break;
+        }
+        items.next();
+        if ch != '\'' && ch != '"' {
+            delimiter.push(ch);
+        }
+    }
+    if delimiter.is_empty() {
+        None
+    } else {
+        Some(delimiter)
+    }
+}
+
+// Skips lines up to and including the one that holds only `delimiter`.
+fn skip_heredoc(items: &mut Items, delimiter: &str) -> Result<(), String> {
+    let mut line = Some(String::new());
+    loop {
+        match items.next() {
+            Some(Item::Char('\n')) | None if line.as_ref().map(|line| line.trim()) == Some(delimiter) => return Ok(()),
+            None => return Err(invalid_heredoc()),
+            Some(Item::Char('\n')) => line = Some(String::new()),
+            Some(Item::Char(ch)) => {
+                if let Some(ref mut line) = line {
+                    line.push(ch);
+                }
+            }
+            // A line holding a value never ends the body.
+            Some(Item::Arg(_)) => line = None,
+        }
+    }
+}
+
+/// Checks a `command!` template the way `commandify` will, as far as that is
+/// possible without the values of its placeholders.
+pub fn check(mut segments: Vec<Piece>) -> Result<(), String> {
+    if let Some(Piece::Text(text)) = segments.first_mut() {
+        *text = text.trim_start().to_string();
+    }
+    if let Some(Piece::Text(text)) = segments.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+    let literal = |word: &Word, text: &str| *word == [Piece::Text(text.to_string())];
+    let is_literal = |word: &Word| word.iter().all(|piece| matches!(*piece, Piece::Text(_)));
+
+    let mut seen_cd = false;
+    let mut seen_export = false;
+    let mut lines = split_lines(segments).into_iter();
+    let mut command_lines = vec![];
+    for line in lines.by_ref() {
+        // Only a line made of plain words can be part of the preamble.
+        let words = match tokenize(line.clone()) {
+            Ok(ref tokens) if tokens.is_empty() => continue,
+            Ok(tokens) => tokens
+                .into_iter()
+                .map(|token| match token {
+                    Token::Word(word) => Some(word),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            Err(_) => None,
+        };
+        let words = match words {
+            Some(words) => words,
+            None => {
+                command_lines.push(line);
+                break;
+            }
+        };
+        if literal(&words[0], "cd") {
+            if seen_cd || seen_export {
+                return Err("cd should be the first line in your command! macro.".into());
+            }
+            // A value may expand to any number of words, so only literal text can be counted.
+            if words.len() != 2 && words.iter().all(is_literal) {
+                return Err(format!("Too many arguments in cd; expected 1, found {}", words.len() - 1));
+            }
+            seen_cd = true;
+        } else if literal(&words[0], "export") {
+            if words.len() < 2 && words.iter().all(is_literal) {
+                return Err("Not enough arguments in export; expected at least 1, found 0".into());
+            }
+            if words[1..].iter().any(|word| is_literal(word) && !word.iter().any(|piece| matches!(*piece, Piece::Text(ref text) if text.contains('=')))) {
+                return Err("Expected export of the format NAME=VALUE".into());
+            }
+            seen_export = true;
+        } else if literal(&words[0], "timeout") && words.len() == 2 {
+            return Err(timeout_preamble());
+        } else {
+            command_lines.push(line);
+            break;
+        }
+    }
+    command_lines.extend(lines);
+
+    let mut command = vec![];
+    for (index, line) in command_lines.into_iter().enumerate() {
+        if index > 0 {
+            command.push(Piece::Text("\n".into()));
+        }
+        command.extend(line);
+    }
+    let command = merge_text(command)
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => Piece::Text(text.replace("\\\n", "\n")),
+            arg => arg,
+        })
+        .collect();
+
+    let mut tokens = tokenize(command)?.into_iter();
+    let mut words = 0;
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(_) => words += 1,
+            Token::Connector("|") => {
+                return Err("command! builds a single Command; use pipeline! or execute! for specs containing |.".into())
+            }
+            Token::Connector(_) => return Err("Only execute! and sequence! accept &&, || and ; between commands.".into()),
+            Token::Redirect => match tokens.next() {
+                Some(Token::Word(_)) => {}
+                _ => return Err("Redirections need a file name, or 1 or 2 after >&, in your command! macro.".into()),
+            },
+            Token::Duplicate | Token::Heredoc => {}
+        }
+    }
+    if words == 0 {
+        return Err("Didn't find a command in your command! macro.".into());
+    }
+    Ok(())
+}
+
+fn is_char(item: Option<&Item>, expected: char) -> bool {
+    match item {
+        Some(Item::Char(ch)) => *ch == expected,
+        _ => false,
+    }
+}
+
+fn parse_quoted(items: &mut Items, words: &mut Words, quote: char) -> Result<(), String> {
+    loop {
+        match items.next() {
+            Some(Item::Char('\\')) => match items.next() {
+                Some(Item::Char(ch)) => {
+                    let escaped = if quote == '"' {
+                        ch == '$' || ch == '`' || ch == '"' || ch == '\\' || ch == '\n'
+                    } else {
+                        ch == '\'' || ch == '\\'
+                    };
+                    if !escaped {
+                        words.push_char('\\');
+                    }
+                    if ch != '\n' || !escaped {
+                        words.push_char(ch);
+                    }
+                }
+                Some(Item::Arg(name)) => {
+                    words.push_char('\\');
+                    words.push_arg(name);
+                }
+                None => return Err(unbalanced()),
+            },
+            Some(Item::Char(ch)) if ch == quote => return Ok(()),
+            Some(Item::Char(ch)) => words.push_char(ch),
+            Some(Item::Arg(name)) => words.push_arg(name),
+            None => return Err(unbalanced()),
+        }
+    }
+}
+
+fn unbalanced() -> String {
+    "Unbalanced quotes or trailing backslash in your command! macro.".into()
+}
+
+fn invalid_heredoc() -> String {
+    "Expected <<WORD, with its lines ending in a line that only holds WORD, in your command! macro.".into()
+}
+
+fn timeout_preamble() -> String {
+    "command! builds a single Command, which can't carry a timeout; use execute! or execute_timeout().".into()
+}
+
+fn unsupported(operator: &str) -> String {
+    format!(
+        "checked_command! builds a single Command and doesn't support `{}`; quote it, or use command!, pipeline! or execute! instead",
+        operator
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Piece::*;
+    use super::*;
+
+    fn text(value: &str) -> Piece {
+        Text(value.to_string())
+    }
+
+    fn arg(value: &str) -> Piece {
+        Arg(value.to_string())
+    }
+
+    #[test]
+    fn segments_and_escapes() {
+        assert_eq!(
+            segments("a {x}b {{c}} { y }").unwrap(),
+            vec![text("a "), arg("x"), text("b {c} "), arg("y")]
+        );
+        assert!(segments("a {").is_err());
+        assert!(segments("a }").is_err());
+        assert!(segments("{x:?}").is_err());
+        assert!(segments("{x-y}").is_err());
+        assert_eq!(
+            segments("{} {1} {}").unwrap(),
+            vec![arg("0"), text(" "), arg("1"), text(" "), arg("1")]
+        );
+    }
+
+    #[test]
+    fn parse_preamble_and_command() {
+        let spec = parse(
+            segments(
+                r#"
+                    cd {dir}
+                    export RUST_LOG=full A={a}
+                    cargo run {flag} --bin="{bin}x" -- 'a b' \
+                        {args}
+                "#,
+            ).unwrap(),
+        ).unwrap();
+        assert_eq!(spec.cd, Some(vec![arg("dir")]));
+        assert_eq!(spec.env, vec![vec![text("RUST_LOG=full")], vec![text("A="), arg("a")]]);
+        assert_eq!(
+            spec.argv,
+            vec![
+                vec![text("cargo")],
+                vec![text("run")],
+                vec![arg("flag")],
+                vec![text("--bin="), arg("bin"), text("x")],
+                vec![text("--")],
+                vec![text("a b")],
+                vec![arg("args")],
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse(segments("export A=1\ncd x\nls").unwrap()).is_err());
+        assert!(parse(segments("cd a b\nls").unwrap()).is_err());
+        assert!(parse(segments("export A\nls").unwrap()).is_err());
+        assert!(parse(segments("cd x").unwrap()).is_err());
+        assert!(parse(segments("echo 'a").unwrap()).is_err());
+        assert!(parse(segments("echo a | cat").unwrap()).is_err());
+        assert!(parse(segments("echo '|' a&b").unwrap()).is_ok());
+        assert!(parse(segments("timeout 5s\nls").unwrap()).is_err());
+        assert!(parse(segments("timeout 5s ls").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn check_command_templates() {
+        assert!(check(segments("cd {dir}\nexport A={a}\ncat < in 2>&1 > {out} <<'EOF'\n'\nEOF").unwrap()).is_ok());
+        assert!(check(segments("cd {a} {b}\nls").unwrap()).is_ok());
+        assert!(check(segments("# comment\n\nls # more").unwrap()).is_ok());
+        assert!(check(segments("echo a | cat").unwrap()).is_err());
+        assert!(check(segments("true && false").unwrap()).is_err());
+        assert!(check(segments("timeout 5s\ntrue").unwrap()).is_err());
+        assert!(check(segments("echo a >").unwrap()).is_err());
+        assert!(check(segments("echo a >&3").unwrap()).is_err());
+        assert!(check(segments("cat <<EOF\nbody").unwrap()).is_err());
+        assert!(check(segments("echo 'a").unwrap()).is_err());
+        assert!(check(segments("export A=1\ncd /\nls").unwrap()).is_err());
+        assert!(check(segments("cd / /tmp\nls").unwrap()).is_err());
+        assert!(check(segments("export A\nls").unwrap()).is_err());
+        assert!(check(segments("2> log").unwrap()).is_err());
+    }
+}
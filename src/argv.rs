@@ -0,0 +1,112 @@
+// Builds argument words out of literal text and interpolated values. Used by
+// the spec lexer and by the code generated by `checked_command!`, which
+// also uses the helpers below for the parts that hold values.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::{canonicalize_path, CommandArg, CommandError};
+
+/// Collects argument words from literal text and interpolated values.
+///
+/// A value splits the same way as in the quoted string form: a list adds one
+/// word per item, and an empty value adds nothing.
+#[derive(Debug, Default)]
+pub struct Argv {
+    words: Vec<OsString>,
+    current: Option<OsString>,
+}
+
+impl Argv {
+    pub fn new() -> Argv {
+        Argv::default()
+    }
+
+    /// Appends to the current word, starting one if needed, even for empty text.
+    pub fn text<S: AsRef<OsStr>>(&mut self, text: S) {
+        self.current.get_or_insert_with(OsString::new).push(text);
+    }
+
+    pub fn push(&mut self, ch: char) {
+        self.text(ch.encode_utf8(&mut [0; 4]));
+    }
+
+    pub fn arg(&mut self, arg: &CommandArg) {
+        match *arg {
+            CommandArg::Empty => {}
+            CommandArg::Literal(ref value) => self.text(value),
+            CommandArg::OsLiteral(ref value) => self.text(value),
+            CommandArg::List(ref list) => {
+                for (index, value) in list.iter().enumerate() {
+                    if index > 0 {
+                        self.end_word();
+                    }
+                    self.text(value);
+                }
+            }
+        }
+    }
+
+    pub fn end_word(&mut self) {
+        if let Some(word) = self.current.take() {
+            self.words.push(word);
+        }
+    }
+
+    pub fn current(&self) -> Option<&OsStr> {
+        self.current.as_deref()
+    }
+
+    pub fn clear_current(&mut self) {
+        self.current = None;
+    }
+
+    /// Ends the current word and removes all finished words.
+    pub fn take_words(&mut self) -> Vec<OsString> {
+        self.end_word();
+        ::std::mem::take(&mut self.words)
+    }
+
+    pub fn into_words(mut self) -> Vec<OsString> {
+        self.take_words()
+    }
+}
+
+/// Splits an `export` item at its first `=`.
+pub fn split_export(item: &OsStr) -> Option<(OsString, OsString)> {
+    let bytes = item.as_encoded_bytes();
+    let index = bytes.iter().position(|&byte| byte == b'=')?;
+    // SAFETY: both halves come from the same OsStr and are split at an ASCII character.
+    unsafe {
+        Some((
+            OsStr::from_encoded_bytes_unchecked(&bytes[..index]).to_owned(),
+            OsStr::from_encoded_bytes_unchecked(&bytes[index + 1..]).to_owned(),
+        ))
+    }
+}
+
+/// Starts a `Command` whose program comes from a value, as `checked_command!`
+/// does when the first word isn't literal text.
+pub fn command(words: Vec<OsString>) -> Result<Command, CommandError> {
+    let mut words = words.into_iter();
+    let mut command = Command::new(words.next().ok_or(CommandError::NoCommand)?);
+    command.args(words);
+    Ok(command)
+}
+
+/// Splits `export` items that hold values, once the values are known.
+pub fn exports(words: Vec<OsString>) -> Result<Vec<(OsString, OsString)>, CommandError> {
+    words
+        .iter()
+        .map(|item| split_export(item).ok_or(CommandError::InvalidExport))
+        .collect()
+}
+
+/// Resolves the `cd` line, which has to come down to a single existing directory.
+pub fn current_dir(words: Vec<OsString>) -> Result<PathBuf, CommandError> {
+    match words.len() {
+        1 => canonicalize_path(Path::new(&words[0])),
+        count => Err(CommandError::TooManyCDArgs(1, count)),
+    }
+}
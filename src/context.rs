@@ -0,0 +1,159 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::shell_quote;
+
+// How much of the end of a captured stderr is kept in an error.
+const STDERR_TAIL_BYTES: usize = 2048;
+
+/// Describes the command behind a `CommandError`, so a failure can be traced
+/// back to the command that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandContext {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    /// The working directory, if it was set on the `Command`.
+    pub cwd: Option<PathBuf>,
+    /// The environment overrides set on the `Command`; `None` means removed.
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// The end of the captured stderr, if the output was captured.
+    pub stderr_tail: Option<String>,
+}
+
+impl CommandContext {
+    pub fn new(command: &Command) -> CommandContext {
+        CommandContext {
+            program: command.get_program().to_owned(),
+            args: command.get_args().map(|arg| arg.to_owned()).collect(),
+            cwd: command.get_current_dir().map(|dir| dir.to_owned()),
+            env: command
+                .get_envs()
+                .map(|(key, value)| (key.to_owned(), value.map(|value| value.to_owned())))
+                .collect(),
+            stderr_tail: None,
+        }
+    }
+
+    /// Keeps the last few kilobytes of `stderr`, starting at a line boundary when cut.
+    pub fn with_stderr(mut self, stderr: &[u8]) -> CommandContext {
+        if stderr.iter().all(u8::is_ascii_whitespace) {
+            return self;
+        }
+        let tail = if stderr.len() > STDERR_TAIL_BYTES {
+            let tail = &stderr[stderr.len() - STDERR_TAIL_BYTES..];
+            let start = tail.iter().position(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+            format!("...\n{}", String::from_utf8_lossy(&tail[start..]))
+        } else {
+            String::from_utf8_lossy(stderr).into_owned()
+        };
+        self.stderr_tail = Some(tail.trim_end().to_string());
+        self
+    }
+
+    /// The command line, quoted so it could be pasted into a shell.
+    pub fn command_line(&self) -> String {
+        Some(&self.program)
+            .into_iter()
+            .chain(&self.args)
+            .map(|arg| shell_quote(&arg.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// A shell line that runs the command again, such as
+    /// `cd /src && env RUST_LOG=full cargo build`.
+    pub fn to_shell_string(&self) -> String {
+        let env = self.env.iter().map(|(key, value)| (key.as_os_str(), value.as_deref()));
+        shell_string(self.cwd.as_deref(), env, &self.command_line())
+    }
+}
+
+/// Prefixes `command_line` with a `cd` to `cwd` and an `env` call that sets
+/// or, for `None`, removes variables. Invalid UTF-8 is replaced.
+pub(crate) fn shell_string<'a, I>(cwd: Option<&Path>, env: I, command_line: &str) -> String
+where
+    I: Iterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
+{
+    let mut line = String::new();
+    if let Some(cwd) = cwd {
+        line.push_str(&format!("cd {} && ", shell_quote(&cwd.to_string_lossy())));
+    }
+    let mut env = env.collect::<Vec<_>>();
+    env.sort();
+    if !env.is_empty() {
+        line.push_str("env ");
+        for (key, value) in env {
+            let key = shell_quote(&key.to_string_lossy());
+            match value {
+                Some(value) => line.push_str(&format!("{}={} ", key, shell_quote(&value.to_string_lossy()))),
+                None => line.push_str(&format!("-u {} ", key)),
+            }
+        }
+    }
+    line.push_str(command_line);
+    line
+}
+
+/// Renders as `` `program args` in cwd with NAME=value``. The stderr tail isn't included.
+impl fmt::Display for CommandContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`", self.command_line())?;
+        if let Some(ref cwd) = self.cwd {
+            write!(f, " in {}", cwd.display())?;
+        }
+        let env = self
+            .env
+            .iter()
+            .filter_map(|(key, value)| {
+                value.as_ref().map(|value| {
+                    format!("{}={}", key.to_string_lossy(), shell_quote(&value.to_string_lossy()))
+                })
+            })
+            .collect::<Vec<_>>();
+        if !env.is_empty() {
+            write!(f, " with {}", env.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommandContext;
+    use std::process::Command;
+
+    #[test]
+    fn context_display() {
+        let mut command = Command::new("cargo");
+        command.args(["build", "a b"]).current_dir("/tmp").env("RUST_LOG", "full");
+        let context = CommandContext::new(&command);
+        assert_eq!(context.to_string(), "`cargo build \"a b\"` in /tmp with RUST_LOG=full");
+    }
+
+    #[test]
+    fn context_shell_string() {
+        let mut command = Command::new("cargo");
+        command.arg("a b").current_dir("/my dir").env("B", "x y").env("A", "1").env_remove("HOME");
+        assert_eq!(
+            CommandContext::new(&command).to_shell_string(),
+            "cd \"/my dir\" && env A=1 B=\"x y\" -u HOME cargo \"a b\""
+        );
+        assert_eq!(CommandContext::new(&Command::new("ls")).to_shell_string(), "ls");
+
+        let mut command = Command::new("ls");
+        command.env("A B", "1").env_remove("C D");
+        assert_eq!(CommandContext::new(&command).to_shell_string(), "env \"A B\"=1 -u \"C D\" ls");
+    }
+
+    #[test]
+    fn context_stderr_tail() {
+        let context = CommandContext::new(&Command::new("x"));
+        assert_eq!(context.clone().with_stderr(b" \n").stderr_tail, None);
+        assert_eq!(context.clone().with_stderr(b"oops\n").stderr_tail, Some("oops".into()));
+
+        let long = format!("{}\nlast line\n", "x".repeat(4000));
+        assert_eq!(context.with_stderr(long.as_bytes()).stderr_tail, Some("...\nlast line".into()));
+    }
+}
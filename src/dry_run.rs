@@ -0,0 +1,201 @@
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{shell_quote, CommandContext, CommandSpec};
+use crate::redirect::Redirect;
+use crate::sequence::Connector;
+use crate::setting::Override;
+
+/// Whether commands are run, or only shown.
+///
+/// In a dry run, every command that would run is rendered as a shell
+/// snippet with its `cd` and `export` lines, and reported instead of spawned.
+/// The command then counts as successful.
+#[derive(Clone, Default)]
+pub enum DryRun {
+    /// Run commands. This is the default.
+    #[default]
+    Off,
+    /// Log each command at info level.
+    Log,
+    /// Pass each command to a callback.
+    Callback(Arc<dyn Fn(&str) + Send + Sync>),
+}
+
+impl DryRun {
+    pub fn callback<F: Fn(&str) + Send + Sync + 'static>(callback: F) -> DryRun {
+        DryRun::Callback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DryRun::Off => write!(f, "Off"),
+            DryRun::Log => write!(f, "Log"),
+            DryRun::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL_DRY_RUN: RefCell<Option<DryRun>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref DRY_RUN: Override<DryRun> = Override::new(DryRun::Off, &LOCAL_DRY_RUN);
+}
+
+/// Sets the dry-run mode for the whole process.
+pub fn set_dry_run(mode: DryRun) {
+    DRY_RUN.set(mode);
+}
+
+/// Runs `f` with `mode` in place of the process-wide mode, on the current
+/// thread only.
+pub fn with_dry_run<R, F: FnOnce() -> R>(mode: DryRun, f: F) -> R {
+    DRY_RUN.with(mode, f)
+}
+
+/// Reports the commands of `contexts`, which make up one pipeline, if this
+/// is a dry run. Returns whether it is, in which case nothing should run.
+pub fn intercept(contexts: &[CommandContext]) -> bool {
+    intercept_pipeline(contexts, &[])
+}
+
+/// Like `intercept`, with the redirects of each stage shown after it.
+pub fn intercept_pipeline(contexts: &[CommandContext], redirects: &[&[Redirect]]) -> bool {
+    report(|| render_pipeline(contexts, redirects))
+}
+
+/// Reports a whole `Sequence` before any of its steps is built, so nothing
+/// is resolved, created or truncated for commands that won't run.
+pub fn intercept_sequence(steps: &[(Connector, Vec<CommandSpec>)]) -> bool {
+    report(|| render_sequence(steps))
+}
+
+fn report<F: FnOnce() -> String>(render: F) -> bool {
+    match DRY_RUN.get() {
+        DryRun::Off => return false,
+        DryRun::Log => info!("Dry run:\n{}", render()),
+        DryRun::Callback(callback) => callback(&render()),
+    }
+    true
+}
+
+// Renders a pipeline as shell lines, with the `cd` and `export` lines of its first stage.
+fn render_pipeline(contexts: &[CommandContext], redirects: &[&[Redirect]]) -> String {
+    let mut lines = vec![];
+    if let Some(first) = contexts.first() {
+        let env = first.env.iter().map(|(key, value)| (key.as_os_str(), value.as_deref()));
+        lines = preamble(first.cwd.as_deref(), env);
+    }
+    let stages = contexts
+        .iter()
+        .enumerate()
+        .map(|(index, context)| (context.command_line(), redirects.get(index).copied().unwrap_or_default()))
+        .collect();
+    render(lines, vec![(Connector::Then, stages)])
+}
+
+// Renders a sequence as shell lines, with the preamble of its first command,
+// which all of them share.
+fn render_sequence(steps: &[(Connector, Vec<CommandSpec>)]) -> String {
+    let mut lines = vec![];
+    if let Some(first) = steps.first().and_then(|(_, specs)| specs.first()) {
+        let mut env = first.env.iter().map(|(key, value)| (key.as_os_str(), Some(value.as_os_str()))).collect::<Vec<_>>();
+        env.sort();
+        lines = preamble(first.cd.as_deref(), env.into_iter());
+    }
+    let steps = steps
+        .iter()
+        .map(|(connector, specs)| {
+            (*connector, specs.iter().map(|spec| (spec.command_line(), &spec.redirects[..])).collect())
+        })
+        .collect();
+    render(lines, steps)
+}
+
+// The `cd`, `export` and `unset` lines that go before the commands. A
+// variable without a value was removed.
+fn preamble<'a, I>(cwd: Option<&Path>, env: I) -> Vec<String>
+where
+    I: Iterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
+{
+    let mut lines = vec![];
+    if let Some(cwd) = cwd {
+        lines.push(format!("cd {}", shell_quote(&cwd.to_string_lossy())));
+    }
+    for (key, value) in env {
+        let key = shell_quote(&key.to_string_lossy());
+        lines.push(match value {
+            Some(value) => format!("export {}={}", key, shell_quote(&value.to_string_lossy())),
+            None => format!("unset {}", key),
+        });
+    }
+    lines
+}
+
+// A command line and the redirects that go after it.
+type Stage<'a> = (String, &'a [Redirect]);
+
+// Renders the steps on one line after `lines`, each a pipeline of commands
+// with their redirects, followed by the bodies of any heredocs.
+fn render(mut lines: Vec<String>, steps: Vec<(Connector, Vec<Stage>)>) -> String {
+    let mut line = String::new();
+    let mut heredocs = vec![];
+    for (index, (connector, stages)) in steps.into_iter().enumerate() {
+        if index > 0 {
+            line.push_str(match connector {
+                Connector::Then => "; ",
+                Connector::And => " && ",
+                Connector::Or => " || ",
+            });
+        }
+        let stages = stages
+            .into_iter()
+            .map(|(command_line, redirects)| {
+                heredocs.extend(redirects.iter().filter_map(Redirect::heredoc_lines));
+                Some(command_line)
+                    .into_iter()
+                    .chain(redirects.iter().map(Redirect::to_shell_string))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        line.push_str(&stages.join(" | "));
+    }
+    lines.push(line);
+    lines.extend(heredocs);
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_pipeline, render_sequence};
+    use crate::{parse_spec, CommandContext};
+    use std::process::Command;
+
+    #[test]
+    fn render_pipeline_lines() {
+        let mut first = Command::new("cat");
+        first.arg("a b").current_dir("/tmp").env("MODE", "x y").env_remove("HOME").env("A B", "1");
+        let second = Command::new("wc");
+        assert_eq!(
+            render_pipeline(&[CommandContext::new(&first), CommandContext::new(&second)], &[]),
+            "cd /tmp\nexport \"A B\"=1\nunset HOME\nexport MODE=\"x y\"\ncat \"a b\" | wc"
+        );
+    }
+
+    #[test]
+    fn render_sequence_lines() {
+        let steps = parse_spec("cd /\nexport B=2 A=1\ncat <<END >out 2>&1 && false || wc -l; true\nbody\nEND".into()).unwrap();
+        assert_eq!(
+            render_sequence(&steps),
+            "cd /\nexport A=1\nexport B=2\ncat <<'EOF' > out 2>&1 && false || wc -l; true\nbody\nEOF"
+        );
+    }
+}
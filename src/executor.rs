@@ -0,0 +1,199 @@
+use std::cell::RefCell;
+use std::io;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{CommandContext, Input, Timeout};
+use crate::setting::Override;
+
+/// Runs commands for `CommandSpecExt`, `Pipeline` and `Sequence`.
+///
+/// Normally commands are spawned directly. Installing an executor with
+/// `set_executor` or `with_executor` hands every command to it instead,
+/// which is how `MockExecutor` keeps tests from spawning anything.
+pub trait Executor: Send + Sync {
+    /// Runs `stages` to completion as a pipeline, with each stage's stdout
+    /// going to the next one's stdin and `input`, if any, to the first one's.
+    /// A plain command is a pipeline of one stage.
+    ///
+    /// Returns an `Output` for every stage, in order, with its exit status
+    /// and what it wrote to stderr. Only the last stage has a stdout.
+    ///
+    /// With a `timeout`, as set by `execute_timeout` or a `timeout` line, the
+    /// stages should be stopped once it runs out, and the run should then
+    /// fail with an error of kind `io::ErrorKind::TimedOut`.
+    fn run(&self, stages: Vec<Command>, input: Option<Input>, timeout: Option<Timeout>) -> io::Result<Vec<Output>>;
+}
+
+// Runs a single command on `executor`.
+pub(crate) fn run_one(
+    executor: &dyn Executor,
+    command: Command,
+    input: Option<Input>,
+    timeout: Option<Timeout>,
+) -> io::Result<Output> {
+    let mut outputs = executor.run(vec![command], input, timeout)?;
+    match outputs.len() {
+        1 => Ok(outputs.remove(0)),
+        count => Err(wrong_count(1, count)),
+    }
+}
+
+pub(crate) fn wrong_count(stages: usize, outputs: usize) -> io::Error {
+    io::Error::other(format!("the executor returned {} outputs for {} stages", outputs, stages))
+}
+
+type Installed = Option<Arc<dyn Executor>>;
+
+thread_local! {
+    static LOCAL_EXECUTOR: RefCell<Option<Installed>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref EXECUTOR: Override<Installed> = Override::new(None, &LOCAL_EXECUTOR);
+}
+
+/// Sets the executor for the whole process; `None` spawns commands directly again.
+pub fn set_executor(executor: Option<Arc<dyn Executor>>) {
+    EXECUTOR.set(executor);
+}
+
+/// Runs `f` with `executor` in place of the process-wide one, on the current
+/// thread only.
+pub fn with_executor<R, F: FnOnce() -> R>(executor: Arc<dyn Executor>, f: F) -> R {
+    EXECUTOR.with(Some(executor), f)
+}
+
+/// The executor that should run commands, if it isn't the default.
+pub fn current() -> Option<Arc<dyn Executor>> {
+    EXECUTOR.get()
+}
+
+/// The scripted result of a command run by `MockExecutor`.
+#[derive(Debug, Clone, Default)]
+pub struct MockResponse {
+    code: i32,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl MockResponse {
+    /// Exits with `code` and writes nothing.
+    pub fn code(code: i32) -> MockResponse {
+        MockResponse { code, ..MockResponse::default() }
+    }
+
+    /// Writes `stdout` to stdout.
+    pub fn stdout<T: Into<Vec<u8>>>(mut self, stdout: T) -> MockResponse {
+        self.stdout = stdout.into();
+        self
+    }
+
+    /// Writes `stderr` to stderr.
+    pub fn stderr<T: Into<Vec<u8>>>(mut self, stderr: T) -> MockResponse {
+        self.stderr = stderr.into();
+        self
+    }
+}
+
+/// What `MockExecutor` was asked to run: the stages of a pipeline, or a
+/// single one for a plain command, the data fed to the first stage, and how
+/// long they were allowed to run.
+#[derive(Debug, Clone)]
+pub struct MockRun {
+    pub stages: Vec<CommandContext>,
+    pub stdin: Option<Vec<u8>>,
+    pub timeout: Option<Duration>,
+}
+
+/// An `Executor` that spawns nothing. It records every run and answers each
+/// stage with the response of the first rule whose words start the command,
+/// or with success and no output if no rule matches.
+#[derive(Debug, Default)]
+pub struct MockExecutor {
+    rules: Mutex<Vec<(Vec<String>, MockResponse)>>,
+    runs: Mutex<Vec<MockRun>>,
+}
+
+impl MockExecutor {
+    pub fn new() -> MockExecutor {
+        MockExecutor::default()
+    }
+
+    /// Answers commands starting with the words of `prefix`, such as
+    /// `git status`, with `response`. The words are split like a spec.
+    pub fn on(&self, prefix: &str, response: MockResponse) -> &MockExecutor {
+        let words = ::shlex::split(prefix).unwrap_or_default();
+        self.rules.lock().unwrap().push((words, response));
+        self
+    }
+
+    /// Every run so far, in order.
+    pub fn runs(&self) -> Vec<MockRun> {
+        self.runs.lock().unwrap().clone()
+    }
+
+    /// Every command run so far, in order, with the stages of a pipeline one
+    /// after the other.
+    pub fn commands(&self) -> Vec<CommandContext> {
+        self.runs().into_iter().flat_map(|run| run.stages).collect()
+    }
+
+    /// The command lines of `commands`, quoted like in error messages.
+    pub fn command_lines(&self) -> Vec<String> {
+        self.commands().iter().map(CommandContext::command_line).collect()
+    }
+
+    fn respond(&self, context: &CommandContext) -> MockResponse {
+        let words = Some(&context.program).into_iter().chain(&context.args).collect::<Vec<_>>();
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(prefix, _)| prefix.len() <= words.len() && prefix.iter().zip(&words).all(|(a, b)| *b == a.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Executor for MockExecutor {
+    fn run(&self, stages: Vec<Command>, input: Option<Input>, timeout: Option<Timeout>) -> io::Result<Vec<Output>> {
+        let stdin = input.map(Input::into_bytes).transpose()?;
+        let stages = stages.iter().map(CommandContext::new).collect::<Vec<_>>();
+        let last = stages.len().saturating_sub(1);
+        let outputs = stages
+            .iter()
+            .enumerate()
+            .map(|(index, context)| {
+                let response = self.respond(context);
+                Output {
+                    status: exit_status(response.code),
+                    // What the other stages write goes to the next one.
+                    stdout: if index == last { response.stdout } else { vec![] },
+                    stderr: response.stderr,
+                }
+            })
+            .collect();
+        self.runs.lock().unwrap().push(MockRun {
+            stages,
+            stdin,
+            timeout: timeout.as_ref().map(Timeout::duration),
+        });
+        Ok(outputs)
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+
+    ExitStatus::from_raw(code as u32)
+}
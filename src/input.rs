@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use super::{dry_run, executor, exit_status_result, io_error, CommandContext, CommandError};
+
+/// Data to feed to a command's stdin.
+///
+/// Strings, byte buffers and files convert into an `Input`; any other reader
+/// can be wrapped with `Input::reader`.
+pub enum Input {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Input {
+    pub fn reader<R: Read + Send + 'static>(reader: R) -> Input {
+        Input::Reader(Box::new(reader))
+    }
+
+    /// Reads all of the input into memory.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Input::Bytes(bytes) => Ok(bytes),
+            Input::Reader(mut reader) => {
+                let mut bytes = vec![];
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl From<&str> for Input {
+    fn from(value: &str) -> Self {
+        Input::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Input {
+    fn from(value: String) -> Self {
+        Input::Bytes(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Input {
+    fn from(value: &[u8]) -> Self {
+        Input::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Input {
+    fn from(value: Vec<u8>) -> Self {
+        Input::Bytes(value)
+    }
+}
+
+impl From<File> for Input {
+    fn from(value: File) -> Self {
+        Input::reader(value)
+    }
+}
+
+/// Writes `input` to `stdin` on a new thread, so a command that writes a lot
+/// before reading all of its input can't deadlock with us. Closes `stdin`
+/// when done. A command that exits without reading everything isn't an error.
+pub fn feed<W: Write + Send + 'static>(input: Input, mut stdin: W) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let result = match input {
+            Input::Bytes(bytes) => stdin.write_all(&bytes),
+            Input::Reader(mut reader) => io::copy(&mut reader, &mut stdin).map(|_| ()),
+        };
+        match result {
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        }
+    })
+}
+
+/// Runs `command` with `input` as its stdin.
+pub fn execute_with_input(mut command: Command, input: Input) -> Result<(), CommandError> {
+    let context = CommandContext::new(&command);
+    if dry_run::intercept(::std::slice::from_ref(&context)) {
+        return Ok(());
+    }
+    if let Some(executor) = executor::current() {
+        let output = executor::run_one(&*executor, command, Some(input), None).map_err(io_error(&context))?;
+        return exit_status_result(output.status, context);
+    }
+    let mut child = command.stdin(Stdio::piped()).spawn().map_err(io_error(&context))?;
+    let writer = feed(input, child.stdin.take().unwrap());
+    let status = child.wait().map_err(io_error(&context))?;
+    let written = writer.join().unwrap();
+    exit_status_result(status, context.clone())?;
+    written.map_err(io_error(&context))
+}
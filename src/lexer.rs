@@ -0,0 +1,522 @@
+// Splits the command section of a spec into words and operators.
+//
+// Quoting follows the same rules as `shlex::split` so existing specs keep
+// their meaning. The difference is that unquoted operators such as `|` are
+// reported as their own tokens instead of being glued into words. Values
+// interpolated into the template are never parsed; they become part of the
+// word they appear in as they are.
+
+use std::ffi::{OsStr, OsString};
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use super::{CommandArg, CommandError};
+use crate::argv::Argv;
+use crate::redirect::{Redirection, Stream};
+use crate::sequence::Connector;
+use crate::template::Segment;
+
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Word(OsString),
+    Pipe,
+    Redirect(Redirection),
+    /// The body of a `<<EOF` block, with the indentation common to its lines removed.
+    Heredoc(OsString),
+    Connector(Connector),
+}
+
+enum Item<'a> {
+    Char(char),
+    Arg(&'a CommandArg),
+}
+
+// The input one character or value at a time, counting the newlines passed.
+struct Items<'a> {
+    items: Peekable<IntoIter<Item<'a>>>,
+    line: usize,
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Item<'a>;
+
+    fn next(&mut self) -> Option<Item<'a>> {
+        let item = self.items.next();
+        if let Some(Item::Char('\n')) = item {
+            self.line += 1;
+        }
+        item
+    }
+}
+
+impl<'a> Items<'a> {
+    fn peek(&mut self) -> Option<&Item<'a>> {
+        self.items.peek()
+    }
+}
+
+/// Fails if the input ends inside a quotation or right after an unescaped
+/// backslash, if a `>&` isn't followed by 1 or 2, or if a `<<` lacks its
+/// delimiter or the line that ends its body.
+pub fn tokenize(segments: &[Segment]) -> Result<Vec<Token>, CommandError> {
+    match tokenize_lines(segments) {
+        Ok(tokens) => Ok(tokens.into_iter().map(|(_, token)| token).collect()),
+        Err((_, err)) => Err(err),
+    }
+}
+
+/// Like `tokenize`, but also gives the line each token starts on, counted
+/// from 0. On failure, the line is where the offending quote, redirection or
+/// heredoc starts.
+pub fn tokenize_lines(segments: &[Segment]) -> Result<Vec<(usize, Token)>, (usize, CommandError)> {
+    let mut items = vec![];
+    for segment in segments {
+        match *segment {
+            Segment::Text(ref text) => items.extend(text.chars().map(Item::Char)),
+            Segment::Arg(ref arg) => items.push(Item::Arg(arg)),
+        }
+    }
+    let mut items = Items {
+        items: items.into_iter().peekable(),
+        line: 0,
+    };
+    let mut tokens = vec![];
+    let mut argv = Argv::new();
+    // Whether the current word used quotes, escapes or values, so `"2">` isn't taken for `2>`.
+    let mut quoted = false;
+    // The line the current word started on.
+    let mut word_line = 0;
+    // Heredocs whose body starts after the current line: the index of their
+    // token, their delimiter and the line of the `<<`.
+    let mut heredocs: Vec<(usize, String, usize)> = vec![];
+
+    while let Some(item) = items.next() {
+        let line = items.line;
+        if argv.current().is_none() {
+            quoted = false;
+            word_line = line;
+        }
+        let ch = match item {
+            Item::Arg(arg) => {
+                quoted = true;
+                // A value after `<` or `>` has to be a single file name.
+                if let Some((_, Token::Redirect(Redirection::Read)))
+                | Some((_, Token::Redirect(Redirection::Write(..)))) = tokens.last()
+                {
+                    match *arg {
+                        CommandArg::List(ref list) if list.len() != 1 => {
+                            return Err((line, CommandError::InvalidRedirect))
+                        }
+                        // Left as an empty word, which the parser rejects.
+                        CommandArg::Empty => argv.text(""),
+                        _ => {}
+                    }
+                }
+                argv.arg(arg);
+                continue;
+            }
+            Item::Char(ch) => ch,
+        };
+        match ch {
+            ' ' | '\t' => flush(&mut argv, &mut tokens, word_line),
+            '\n' => {
+                flush(&mut argv, &mut tokens, word_line);
+                for (index, delimiter, line) in heredocs.drain(..) {
+                    let body = read_heredoc(&mut items, &delimiter).ok_or((line, CommandError::InvalidHeredoc))?;
+                    tokens[index].1 = Token::Heredoc(body);
+                }
+            }
+            // The newline ending a comment is left for the case above.
+            '#' if argv.current().is_none() => {
+                while items.peek().is_some() && !is_char(items.peek(), '\n') {
+                    items.next();
+                }
+            }
+            '|' => {
+                flush(&mut argv, &mut tokens, word_line);
+                if is_char(items.peek(), '|') {
+                    items.next();
+                    tokens.push((line, Token::Connector(Connector::Or)));
+                } else {
+                    tokens.push((line, Token::Pipe));
+                }
+            }
+            // A single & has no special meaning here and stays part of the word.
+            '&' if is_char(items.peek(), '&') => {
+                items.next();
+                flush(&mut argv, &mut tokens, word_line);
+                tokens.push((line, Token::Connector(Connector::And)));
+            }
+            ';' => {
+                flush(&mut argv, &mut tokens, word_line);
+                tokens.push((line, Token::Connector(Connector::Then)));
+            }
+            '<' => {
+                flush(&mut argv, &mut tokens, word_line);
+                if is_char(items.peek(), '<') {
+                    items.next();
+                    let delimiter = read_delimiter(&mut items).ok_or((line, CommandError::InvalidHeredoc))?;
+                    heredocs.push((tokens.len(), delimiter, line));
+                    tokens.push((line, Token::Heredoc(OsString::new())));
+                } else {
+                    tokens.push((line, Token::Redirect(Redirection::Read)));
+                }
+            }
+            '>' => {
+                // A bare 1 or 2 right before > selects the stream, like in a shell.
+                let stream = match argv.current() {
+                    Some(word) if !quoted && word == OsStr::new("2") => Some(Stream::Stderr),
+                    Some(word) if !quoted && word == OsStr::new("1") => Some(Stream::Stdout),
+                    _ => None,
+                };
+                if stream.is_some() {
+                    argv.clear_current();
+                }
+                flush(&mut argv, &mut tokens, word_line);
+                let stream = stream.unwrap_or(Stream::Stdout);
+                let redirection = if is_char(items.peek(), '>') {
+                    items.next();
+                    Redirection::Write(stream, true)
+                } else if is_char(items.peek(), '&') {
+                    items.next();
+                    match items.next() {
+                        Some(Item::Char('1')) => Redirection::Duplicate(stream, Stream::Stdout),
+                        Some(Item::Char('2')) => Redirection::Duplicate(stream, Stream::Stderr),
+                        _ => return Err((line, CommandError::InvalidRedirect)),
+                    }
+                } else {
+                    Redirection::Write(stream, false)
+                };
+                tokens.push((line, Token::Redirect(redirection)));
+            }
+            '\\' => match items.next() {
+                // \<newline> continues the line
+                Some(Item::Char('\n')) => {}
+                Some(Item::Char(ch2)) => {
+                    quoted = true;
+                    argv.push(ch2);
+                }
+                // A value is never escaped; the backslash stays.
+                Some(Item::Arg(arg)) => {
+                    quoted = true;
+                    argv.push('\\');
+                    argv.arg(arg);
+                }
+                None => return Err((line, CommandError::UnbalancedQuotes)),
+            },
+            '\'' => {
+                quoted = true;
+                argv.text("");
+                parse_single(&mut items, &mut argv).ok_or((line, CommandError::UnbalancedQuotes))?
+            }
+            '"' => {
+                quoted = true;
+                argv.text("");
+                parse_double(&mut items, &mut argv).ok_or((line, CommandError::UnbalancedQuotes))?
+            }
+            _ => argv.push(ch),
+        }
+    }
+    flush(&mut argv, &mut tokens, word_line);
+    if let Some(&(_, _, line)) = heredocs.first() {
+        return Err((line, CommandError::InvalidHeredoc));
+    }
+
+    Ok(tokens)
+}
+
+// Reads the word after `<<`. Quotes around it are dropped; the body is never
+// expanded either way.
+fn read_delimiter(items: &mut Items) -> Option<String> {
+    while is_char(items.peek(), ' ') || is_char(items.peek(), '\t') {
+        items.next();
+    }
+    let mut delimiter = String::new();
+    while let Some(&Item::Char(ch)) = items.peek() {
+        if ch.is_whitespace() || "|&;<>".contains(ch) {
+            break;
+        }
+        items.next();
+        if ch != '\'' && ch != '"' {
+            delimiter.push(ch);
+        }
+    }
+    if delimiter.is_empty() {
+        None
+    } else {
+        Some(delimiter)
+    }
+}
+
+// Reads lines up to and including the one that holds only `delimiter`.
+fn read_heredoc(items: &mut Items, delimiter: &str) -> Option<OsString> {
+    let mut lines = vec![];
+    let mut line = vec![];
+    loop {
+        let item = items.next();
+        match item {
+            Some(Item::Char('\n')) | None => {
+                let text = line
+                    .iter()
+                    .map(|item| match *item {
+                        Item::Char(ch) => Some(ch),
+                        Item::Arg(_) => None,
+                    })
+                    .collect::<Option<String>>();
+                if text.as_ref().map(|text| text.trim()) == Some(delimiter) {
+                    break;
+                }
+                item.as_ref()?;
+                lines.push(::std::mem::take(&mut line));
+            }
+            Some(item) => line.push(item),
+        }
+    }
+
+    let is_indent = |item: &Item| matches!(*item, Item::Char(' ') | Item::Char('\t'));
+    let indent = lines
+        .iter()
+        .filter(|line| !line.iter().all(is_indent))
+        .map(|line| line.iter().take_while(|item| is_indent(item)).count())
+        .min()
+        .unwrap_or(0);
+    let mut body = OsString::new();
+    for line in lines {
+        let skip = line.iter().take(indent).take_while(|item| is_indent(item)).count();
+        for item in line.into_iter().skip(skip) {
+            match item {
+                Item::Char(ch) => body.push(ch.encode_utf8(&mut [0; 4])),
+                // A list is written the way it would be printed, separated by spaces.
+                Item::Arg(CommandArg::List(list)) => body.push(list.join(" ")),
+                Item::Arg(CommandArg::Literal(value)) => body.push(value),
+                Item::Arg(CommandArg::OsLiteral(value)) => body.push(value),
+                Item::Arg(CommandArg::Empty) => {}
+            }
+        }
+        body.push("\n");
+    }
+    Some(body)
+}
+
+fn flush(argv: &mut Argv, tokens: &mut Vec<(usize, Token)>, line: usize) {
+    tokens.extend(argv.take_words().into_iter().map(|word| (line, Token::Word(word))));
+}
+
+fn is_char(item: Option<&Item>, expected: char) -> bool {
+    match item {
+        Some(&Item::Char(ch)) => ch == expected,
+        _ => false,
+    }
+}
+
+fn parse_double(items: &mut Items, argv: &mut Argv) -> Option<()> {
+    loop {
+        match items.next()? {
+            Item::Char('\\') => match items.next()? {
+                // \$ => $
+                Item::Char(ch @ '$') | Item::Char(ch @ '`') | Item::Char(ch @ '"') | Item::Char(ch @ '\\') => {
+                    argv.push(ch)
+                }
+                // \<newline> => nothing
+                Item::Char('\n') => {}
+                // \x => \x
+                Item::Char(ch) => {
+                    argv.push('\\');
+                    argv.push(ch);
+                }
+                Item::Arg(arg) => {
+                    argv.push('\\');
+                    argv.arg(arg);
+                }
+            },
+            Item::Char('"') => return Some(()),
+            Item::Char(ch) => argv.push(ch),
+            Item::Arg(arg) => argv.arg(arg),
+        }
+    }
+}
+
+fn parse_single(items: &mut Items, argv: &mut Argv) -> Option<()> {
+    loop {
+        match items.next()? {
+            Item::Char('\\') => match items.next()? {
+                // for single quotes, only these can be escaped
+                Item::Char(ch @ '\'') | Item::Char(ch @ '\\') => argv.push(ch),
+                Item::Char(ch) => {
+                    argv.push('\\');
+                    argv.push(ch);
+                }
+                Item::Arg(arg) => {
+                    argv.push('\\');
+                    argv.arg(arg);
+                }
+            },
+            Item::Char('\'') => return Some(()),
+            Item::Char(ch) => argv.push(ch),
+            Item::Arg(arg) => argv.arg(arg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Token::*;
+    use crate::redirect::Redirection::*;
+    use crate::redirect::Stream::*;
+    use crate::sequence::Connector::*;
+
+    use crate::template::Template;
+    use crate::CommandArg;
+
+    fn tokenize<T: Into<Template>>(input: T) -> Result<Vec<super::Token>, crate::CommandError> {
+        super::tokenize(&input.into().into_segments())
+    }
+
+    fn words(list: &[&str]) -> Vec<super::Token> {
+        list.iter().map(|x| Word(x.into())).collect()
+    }
+
+    #[test]
+    fn tokenize_matches_shlex() {
+        for input in &[
+            "foo$baz",
+            "foo baz",
+            "foo\"bar\"baz",
+            "foo \"bar\"baz",
+            "   foo \nbar",
+            "foo\\\nbar",
+            "\"foo\\\nbar\"",
+            "'baz\\$b'",
+            "'baz\\''",
+            "foo #bar\nbaz",
+            "foo\\ bar \"\"",
+        ] {
+            let expected = ::shlex::split(input).unwrap();
+            let expected = expected.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+            assert_eq!(tokenize(*input).unwrap(), words(&expected), "{:?}", input);
+        }
+        for input in &["\\", "\"\\", "'\\", "\"", "'"] {
+            assert!(tokenize(*input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn tokenize_pipes() {
+        assert_eq!(
+            tokenize("cat x|grep 'a|b' \"|\" | wc").unwrap(),
+            vec![
+                Word("cat".into()),
+                Word("x".into()),
+                Pipe,
+                Word("grep".into()),
+                Word("a|b".into()),
+                Word("|".into()),
+                Pipe,
+                Word("wc".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_redirects() {
+        assert_eq!(
+            tokenize("cmd <in >out 2>>err \"2\">x a2>y 2>&1 >&2").unwrap(),
+            vec![
+                Word("cmd".into()),
+                Redirect(Read),
+                Word("in".into()),
+                Redirect(Write(Stdout, false)),
+                Word("out".into()),
+                Redirect(Write(Stderr, true)),
+                Word("err".into()),
+                Word("2".into()),
+                Redirect(Write(Stdout, false)),
+                Word("x".into()),
+                Word("a2".into()),
+                Redirect(Write(Stdout, false)),
+                Word("y".into()),
+                Redirect(Duplicate(Stderr, Stdout)),
+                Redirect(Duplicate(Stdout, Stderr)),
+            ]
+        );
+        assert!(tokenize("cmd 2>&3").is_err());
+    }
+
+    #[test]
+    fn tokenize_connectors() {
+        assert_eq!(
+            tokenize("a&&b||c;d 'e;f' x&y").unwrap(),
+            vec![
+                Word("a".into()),
+                Connector(And),
+                Word("b".into()),
+                Connector(Or),
+                Word("c".into()),
+                Connector(Then),
+                Word("d".into()),
+                Word("e;f".into()),
+                Word("x&y".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_values() {
+        let template = Template::new()
+            .text("echo a")
+            .arg(CommandArg::Literal("b c|d".into()))
+            .text(" '")
+            .arg(CommandArg::Literal("'\\$x".into()))
+            .text("' ")
+            .arg(CommandArg::List(vec!["1".into(), "2".into()]))
+            .text(">")
+            .arg(CommandArg::Literal("".into()))
+            .arg(CommandArg::Empty);
+        assert_eq!(
+            tokenize(template).unwrap(),
+            vec![
+                Word("echo".into()),
+                Word("ab c|d".into()),
+                Word("'\\$x".into()),
+                Word("1".into()),
+                Word("2".into()),
+                Redirect(Write(Stdout, false)),
+                Word("".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_heredocs() {
+        let template = Template::new()
+            .text("cat <<EOF | grep 'x' # note\n    a $b\n      ")
+            .arg(CommandArg::Literal("| 'c'".into()))
+            .text("\n\n    EOF\nwc <<'END'\nEND\n");
+        assert_eq!(
+            tokenize(template).unwrap(),
+            vec![
+                Word("cat".into()),
+                Heredoc("a $b\n  | 'c'\n\n".into()),
+                Pipe,
+                Word("grep".into()),
+                Word("x".into()),
+                Word("wc".into()),
+                Heredoc("".into()),
+            ]
+        );
+        assert!(tokenize("cat <<EOF\nbody").is_err());
+        assert!(tokenize("cat <<\nEOF").is_err());
+    }
+
+    #[test]
+    fn tokenize_with_lines() {
+        let lines = |input: &str| super::tokenize_lines(&Template::from(input).into_segments());
+        let tokens = lines("a 'b\nc' \\\n| d <<EOF\nx\nEOF\ne").unwrap();
+        let tokens = tokens.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+        assert_eq!(tokens, vec![0, 0, 2, 2, 2, 5]);
+
+        assert_eq!(lines("a\nb 'c\n\nd").unwrap_err().0, 1);
+        assert_eq!(lines("a\n\nb <<EOF | c <<END\nEOF\n").unwrap_err().0, 2);
+        assert_eq!(lines("a\nb >&3").unwrap_err().0, 1);
+    }
+}
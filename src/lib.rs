@@ -1,4 +1,5 @@
 extern crate shlex;
+extern crate tb2f_commandspec_macros;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -11,23 +12,82 @@ extern crate nix;
 #[cfg(windows)]
 extern crate winapi;
 
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output};
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 
 pub mod macros;
+#[doc(hidden)]
+pub mod argv;
+mod context;
+mod dry_run;
+mod executor;
+mod input;
+mod lexer;
+mod lines;
+mod pipeline;
 mod process;
+#[cfg(feature = "serde")]
+mod recording;
+mod redirect;
+mod sequence;
+mod setting;
+#[cfg(feature = "serde")]
+mod serialize;
+mod shell;
 mod signal;
+mod spec_file;
+mod tee;
+mod template;
+mod timeout;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
+use lexer::Token;
+use redirect::{Redirect, Redirection, StageIo};
+use sequence::Connector;
 use process::Process;
-use signal::Signal;
+use template::Segment;
+
+pub use context::CommandContext;
+pub use dry_run::{set_dry_run, with_dry_run, DryRun};
+pub use executor::{set_executor, with_executor, Executor, MockExecutor, MockResponse, MockRun};
+pub use input::Input;
+pub use redirect::Stream;
+pub use pipeline::Pipeline;
+#[cfg(feature = "serde")]
+pub use recording::{Recorder, Replayer};
+pub use sequence::Sequence;
+pub use shell::{set_shell, with_shell, Shell};
+pub use signal::Signal;
+pub use spec_file::SpecFile;
+pub use tee::Tee;
+pub use template::Template;
+pub use timeout::Timeout;
+#[doc(hidden)]
+pub use tb2f_commandspec_macros::{__checked_command, __checked_template, __command_format, __command_template};
 
 lazy_static! {
-    static ref PID_MAP: Arc<Mutex<HashMap<i32, Process>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref PID_MAP: Arc<Mutex<HashMap<i32, Arc<Process>>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+// Makes a process group reachable by `cleanup_on_ctrlc` while it runs. It is
+// in a group of its own, so it doesn't get the terminal's SIGINT otherwise.
+fn register(process: Process) -> Arc<Process> {
+    let process = Arc::new(process);
+    PID_MAP.lock().unwrap().insert(process.id(), process.clone());
+    process
+}
+
+fn unregister(process: &Process) {
+    if let Some(process) = PID_MAP.lock().unwrap().remove(&process.id()) { process.reap() }
 }
 
 // This is basically what failure does but without bail!
@@ -67,11 +127,135 @@ pub fn cleanup_on_ctrlc() {
     });
 }
 
-pub struct SpawnGuard(i32);
+/// What happens to a still running command when its `SpawnGuard` is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DropPolicy {
+    /// Leave it running. This is the default.
+    #[default]
+    Detach,
+    /// Block until it exits.
+    Wait,
+    /// Send SIGTERM to its process group, then SIGKILL once the command
+    /// exited or the grace period is over, so no grandchildren are left behind.
+    Terminate(Duration),
+}
+
+/// A command started by `scoped_spawn`, running in its own process group.
+///
+/// In a dry run, nothing was started: the guard acts like the command
+/// already exited successfully, and `id()` is 0. The same goes for an
+/// installed `Executor`, which runs the command to completion in `scoped_spawn`.
+pub struct SpawnGuard {
+    process: Option<Arc<Process>>,
+    // The exit status when there is no process.
+    finished: ExitStatus,
+    context: CommandContext,
+    drop_policy: DropPolicy,
+    // Set by the watchdog of `scoped_spawn_timeout` when it stopped the
+    // command, or when an executor reported that it ran out of time.
+    timed_out: Arc<Mutex<Option<Duration>>>,
+}
+
+impl SpawnGuard {
+    fn new(process: Process, context: CommandContext) -> SpawnGuard {
+        SpawnGuard {
+            process: Some(register(process)),
+            finished: ExitStatus::default(),
+            context,
+            drop_policy: DropPolicy::default(),
+            timed_out: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Stands in for a command that didn't run in a process of its own.
+    fn finished(context: CommandContext, status: ExitStatus) -> SpawnGuard {
+        SpawnGuard {
+            process: None,
+            finished: status,
+            context,
+            drop_policy: DropPolicy::default(),
+            timed_out: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets what happens to the command when this guard is dropped.
+    pub fn drop_policy(mut self, policy: DropPolicy) -> SpawnGuard {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// The id of the process group, which is the pid of the command on Unix.
+    pub fn id(&self) -> i32 {
+        self.process.as_ref().map_or(0, |process| process.id())
+    }
+
+    /// Sends `signal` to the whole process group.
+    pub fn signal(&self, signal: Signal) {
+        if let Some(ref process) = self.process {
+            process.signal(signal);
+        }
+    }
+
+    /// The exit status, if the command has exited.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match self.process {
+            Some(ref process) => process.try_status().ok().and_then(|status| status),
+            None => Some(self.finished),
+        }
+    }
+
+    /// Returns `None` while the command is running, and then the same result as `execute`.
+    pub fn try_wait(&self) -> Option<Result<(), CommandError>> {
+        let status = match self.process {
+            Some(ref process) => match process.try_status() {
+                Ok(None) => return None,
+                Ok(Some(status)) => status,
+                Err(err) => return Some(Err(io_error(&self.context)(err))),
+            },
+            None => self.finished,
+        };
+        Some(match *self.timed_out.lock().unwrap() {
+            Some(elapsed) => Err(CommandError::Timeout(elapsed, Box::new(self.context.clone()))),
+            None => exit_status_result(status, self.context.clone()),
+        })
+    }
+
+    /// Waits for the command to exit and returns the same result as `execute`.
+    pub fn wait(&self) -> Result<(), CommandError> {
+        // Polls, so the Ctrl-C handler can still reach the process in the meantime.
+        loop {
+            if let Some(result) = self.try_wait() {
+                return result;
+            }
+            ::std::thread::sleep(timeout::POLL_INTERVAL);
+        }
+    }
+}
 
 impl ::std::ops::Drop for SpawnGuard {
     fn drop(&mut self) {
-        if let Some(process) = PID_MAP.lock().unwrap().remove(&self.0) { process.reap() }
+        let process = match self.process {
+            Some(ref process) => process.clone(),
+            None => return,
+        };
+        match self.drop_policy {
+            DropPolicy::Detach => {}
+            DropPolicy::Wait => {
+                let _ = self.wait();
+            }
+            DropPolicy::Terminate(grace) => {
+                process.signal(Signal::SIGTERM);
+                let deadline = Instant::now() + grace;
+                while !process.exited().unwrap_or(true) && Instant::now() < deadline {
+                    ::std::thread::sleep(timeout::POLL_INTERVAL);
+                }
+                // Even if the command exited, or was waited for already, the
+                // rest of its group may not have.
+                process.signal(Signal::SIGKILL);
+                let _ = process.status();
+            }
+        }
+        unregister(&process);
     }
 }
 
@@ -80,88 +264,299 @@ impl ::std::ops::Drop for SpawnGuard {
 pub trait CommandSpecExt {
     fn execute(self) -> Result<(), CommandError>;
 
+    /// Like `execute`, but captures stdout and stderr and returns them.
+    /// On failure the error is a `CommandError::Output` holding the captured output.
+    fn execute_output(self) -> Result<Output, CommandError>;
+
+    /// Like `execute_output`, but returns only stdout, which must be UTF-8.
+    fn execute_stdout(self) -> Result<String, CommandError> where Self: Sized {
+        self.execute_output().and_then(|output| String::from_utf8(output.stdout).map_err(CommandError::Utf8))
+    }
+
+    /// Like `execute_output`, but returns only stderr, which must be UTF-8.
+    fn execute_stderr(self) -> Result<String, CommandError> where Self: Sized {
+        self.execute_output().and_then(|output| String::from_utf8(output.stderr).map_err(CommandError::Utf8))
+    }
+
+    /// Like `execute`, but writes `input` to the command's stdin. The input is
+    /// written on its own thread, so the command can produce output meanwhile.
+    fn execute_with_input<I: Into<Input>>(self, input: I) -> Result<(), CommandError> where Self: Sized;
+
+    /// Like `execute`, but calls `on_line` with every line the command writes,
+    /// as soon as it is written. On failure, the error holds the last `keep`
+    /// lines of stderr.
+    fn execute_lines<F>(self, keep: usize, on_line: F) -> Result<(), CommandError>
+    where
+        Self: Sized,
+        F: FnMut(Stream, &str);
+
+    /// Like `execute`, but copies stdout and stderr to the terminal and to the
+    /// sinks of `tee` while the command runs.
+    fn execute_tee(self, tee: Tee) -> Result<(), CommandError>;
+
+    /// Like `execute`, but stops the command once `timeout` runs out and
+    /// fails with `CommandError::Timeout`. The command runs in its own process group.
+    fn execute_timeout(self, timeout: Timeout) -> Result<(), CommandError>;
+
     fn scoped_spawn(self) -> Result<SpawnGuard, ::std::io::Error>;
+
+    /// Like `scoped_spawn`, but stops the command in the background once `timeout` runs out.
+    fn scoped_spawn_timeout(self, timeout: Timeout) -> Result<SpawnGuard, ::std::io::Error>;
 }
 
 #[derive(Debug)]
 pub enum CommandError {
-    Io(::std::io::Error),
-    Interrupt,
-    Code(i32),
+    /// The command is known when the error came from spawning or waiting for it.
+    Io(::std::io::Error, Option<Box<CommandContext>>),
+    Interrupt(Box<CommandContext>),
+    Code(i32, Box<CommandContext>),
+    /// The command was killed by a signal; the flag is set if it dumped core. Unix only.
+    Signaled(Signal, bool, Box<CommandContext>),
+    /// The command was stopped because it ran out of time; holds how long it had run.
+    Timeout(Duration, Box<CommandContext>),
     TooManyCDArgs(usize,usize),
     NotEnoughExportArgs(usize,usize),
     NoChangeDir,
     InvalidExport,
     ExportMispositioned,
-    NoCommand
+    NoCommand,
+    UnbalancedQuotes,
+    EmptyPipeStage,
+    UnexpectedPipe,
+    EmptySequenceStep,
+    UnexpectedSequence,
+    InvalidRedirect,
+    InvalidHeredoc,
+    InvalidTimeout,
+    UnexpectedTimeout,
+    /// A `Command` removes this variable from the environment, which a `CommandSpec` can't express.
+    EnvRemoved(OsString),
+    /// A `CommandSpec` couldn't be read from or written to JSON, TOML or YAML.
+    Serialization(String),
+    /// A `{name}` in a spec file has no value, or a brace isn't closed.
+    InvalidParameter(String),
+    /// Something went wrong with a `SpecFile`; holds its path, the line if known, and the reason.
+    SpecFile(PathBuf, Option<usize>, Box<CommandError>),
+    /// A file named in a redirection couldn't be opened.
+    Redirect(PathBuf, ::std::io::Error),
+    /// A stage of a `Pipeline` failed; holds its zero-based index and the reason.
+    Stage(usize, Box<CommandError>),
+    /// A step of a `Sequence` failed; holds its zero-based index and the reason.
+    Step(usize, Box<CommandError>),
+    /// A command run with captured output failed; holds the reason and what it wrote.
+    Output(Box<CommandError>, Output),
+    /// Captured output wasn't valid UTF-8.
+    Utf8(::std::string::FromUtf8Error),
 }
 
 impl std::fmt::Display for CommandError
 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
         match self {
-            CommandError::Io(err) => write!(f,"{}",format_args!("Encountered an IO error: {:?}",err)),
-            CommandError::Interrupt => write!(f, "Command was interrupted."),
-            CommandError::Code(code) => write!(f, "{}",format_args!("Command failed with error code {}",code)),
+            CommandError::Io(err, None) => write!(f,"{}",format_args!("Encountered an IO error: {:?}",err)),
+            CommandError::Io(err, Some(context)) => {
+                write!(f,"{}",format_args!("Encountered an IO error: {:?} (running {})",err,context))?;
+                write_stderr_tail(f, context)
+            }
+            CommandError::Interrupt(context) => {
+                write!(f, "{}",format_args!("Command was interrupted (running {})",context))?;
+                write_stderr_tail(f, context)
+            }
+            CommandError::Code(code, context) => {
+                write!(f, "{}",format_args!("Command failed with error code {} (running {})",code,context))?;
+                write_stderr_tail(f, context)
+            }
+            CommandError::Signaled(signal, core_dumped, context) => {
+                let core = if *core_dumped { ", core dumped" } else { "" };
+                write!(f, "{}",format_args!("Command was killed by {:?}{} (running {})",signal,core,context))?;
+                write_stderr_tail(f, context)
+            }
             CommandError::TooManyCDArgs(expected,found) => write!(f, "{}",format_args!("Too many arguments in cd; expected {}, found {}",expected,found)),
             CommandError::NotEnoughExportArgs(expected,found) => write!(f, "{}",format_args!("Not enough arguments in export; expected at least {}, found {}",expected,found)),
             CommandError::NoChangeDir => write!(f, "cd should be the first line in your command! macro."),
             CommandError::InvalidExport => write!(f, "Expected export of the format NAME=VALUE"),
             CommandError::ExportMispositioned => write!(f, "exports should follow cd but precede your command in the command! macro."),
             CommandError::NoCommand => write!(f, "Didn't find a command in your command! macro."),
+            CommandError::UnbalancedQuotes => write!(f, "Unbalanced quotes or trailing backslash in your command! macro."),
+            CommandError::EmptyPipeStage => write!(f, "Expected a command on both sides of | in your command! macro."),
+            CommandError::UnexpectedPipe => write!(f, "command! builds a single Command; use pipeline! or execute! for specs containing |."),
+            CommandError::EmptySequenceStep => write!(f, "Expected a command on both sides of &&, || and ; in your command! macro."),
+            CommandError::UnexpectedSequence => write!(f, "Only execute! and sequence! accept &&, || and ; between commands."),
+            CommandError::InvalidRedirect => write!(f, "Redirections need a single file name, or 1 or 2 after >&, in your command! macro."),
+            CommandError::InvalidHeredoc => write!(f, "Expected <<WORD, with its lines ending in a line that only holds WORD, in your command! macro."),
+            CommandError::Timeout(elapsed, context) => {
+                write!(f, "{}",format_args!("Command timed out after {:.1}s (running {})",elapsed.as_secs_f64(),context))?;
+                write_stderr_tail(f, context)
+            }
+            CommandError::InvalidTimeout => write!(f, "Expected a timeout such as 30s, 500ms or 2m in your command! macro."),
+            CommandError::UnexpectedTimeout => write!(f, "A single Command can't carry a timeout; use execute!, execute_timeout() or CommandSpec::to_pipeline()."),
+            CommandError::EnvRemoved(key) => write!(f, "{}",format_args!("A CommandSpec can't remove the environment variable {}",key.to_string_lossy())),
+            CommandError::Serialization(err) => write!(f, "{}",format_args!("Couldn't convert the command spec: {}",err)),
+            CommandError::InvalidParameter(name) => write!(f, "{}",format_args!("No value for the parameter {{{}}}, or a brace that isn't closed; write {{{{ and }}}} for literal braces.",name)),
+            CommandError::SpecFile(path, Some(line), err) => write!(f, "{}",format_args!("{}:{}: {}",path.display(),line,err)),
+            CommandError::SpecFile(path, None, err) => write!(f, "{}",format_args!("{}: {}",path.display(),err)),
+            CommandError::Redirect(path, err) => write!(f, "{}",format_args!("Couldn't open {} for redirection: {}",path.display(),err)),
+            CommandError::Stage(index, err) => write!(f, "{}",format_args!("Stage {} of the pipeline failed: {}",index + 1,err)),
+            CommandError::Step(index, err) => write!(f, "{}",format_args!("Step {} of the sequence failed: {}",index + 1,err)),
+            // The stderr tail is already part of the context of `err`.
+            CommandError::Output(err, _) => write!(f, "{}", err),
+            CommandError::Utf8(err) => write!(f, "{}",format_args!("Command output was not valid UTF-8: {}",err)),
         }
     }
 }
 
+fn write_stderr_tail(f: &mut fmt::Formatter, context: &CommandContext) -> fmt::Result {
+    match context.stderr_tail {
+        Some(ref tail) => write!(f, "{}",format_args!("; stderr:\n{}",tail)),
+        None => Ok(()),
+    }
+}
+
 impl CommandError {
     /// Returns the error code this command failed with. Can panic if not a `Code`.
+    /// For a failed pipeline stage or sequence step, returns the code of that stage or step.
     pub fn error_code(&self) -> i32 {
-        if let CommandError::Code(value) = *self {
-            value
-        } else {
-            panic!("Called error_code on a value that was not a CommandError::Code")
+        match *self {
+            CommandError::Code(value, _) => value,
+            CommandError::Stage(_, ref err) | CommandError::Step(_, ref err) => err.error_code(),
+            CommandError::Output(ref err, _) => err.error_code(),
+            _ => panic!("Called error_code on a value that was not a CommandError::Code"),
         }
     }
 }
 
+fn exit_status_result(status: ExitStatus, context: CommandContext) -> Result<(), CommandError> {
+    if status.success() {
+        Ok(())
+    } else if let Some(code) = status.code() {
+        Err(CommandError::Code(code, Box::new(context)))
+    } else if let Some((signal, core_dumped)) = terminating_signal(status) {
+        Err(CommandError::Signaled(signal, core_dumped, Box::new(context)))
+    } else {
+        Err(CommandError::Interrupt(Box::new(context)))
+    }
+}
+
+#[cfg(unix)]
+fn terminating_signal(status: ExitStatus) -> Option<(Signal, bool)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let signal = Signal::from_c_int(status.signal()?).ok()?;
+    Some((signal, status.core_dumped()))
+}
+
+#[cfg(not(unix))]
+fn terminating_signal(_status: ExitStatus) -> Option<(Signal, bool)> {
+    None
+}
+
+fn io_error(context: &CommandContext) -> impl Fn(::std::io::Error) -> CommandError + '_ {
+    move |err| CommandError::Io(err, Some(Box::new(context.clone())))
+}
+
 impl CommandSpecExt for Command {
     // Executes the command, and returns a versatile error struct
     fn execute(mut self) -> Result<(), CommandError> {
+        let context = CommandContext::new(&self);
+        if dry_run::intercept(::std::slice::from_ref(&context)) {
+            return Ok(());
+        }
+        if let Some(executor) = executor::current() {
+            let output = executor::run_one(&*executor, self, None, None).map_err(io_error(&context))?;
+            return exit_status_result(output.status, context);
+        }
         match self.spawn() {
             Ok(mut child) => {
                 match child.wait() {
-                    Ok(status) => {
-                        if status.success() {
-                            Ok(())
-                        } else if let Some(code) = status.code() {
-                            Err(CommandError::Code(code))
-                        } else {
-                            Err(CommandError::Interrupt)
-                        }
-                    }
+                    Ok(status) => exit_status_result(status, context),
                     Err(err) => {
-                        Err(CommandError::Io(err))
+                        Err(io_error(&context)(err))
                     }
                 }
             },
-            Err(err) => Err(CommandError::Io(err)),
+            Err(err) => Err(io_error(&context)(err)),
         }
     }
 
+    fn execute_output(mut self) -> Result<Output, CommandError> {
+        let context = CommandContext::new(&self);
+        if dry_run::intercept(::std::slice::from_ref(&context)) {
+            return Ok(Output { status: ExitStatus::default(), stdout: vec![], stderr: vec![] });
+        }
+        let output = match executor::current() {
+            Some(executor) => executor::run_one(&*executor, self, None, None),
+            None => self.output(),
+        };
+        let output = output.map_err(io_error(&context))?;
+        match exit_status_result(output.status, context.with_stderr(&output.stderr)) {
+            Ok(()) => Ok(output),
+            Err(err) => Err(CommandError::Output(Box::new(err), output)),
+        }
+    }
+
+    fn execute_with_input<I: Into<Input>>(self, input: I) -> Result<(), CommandError> {
+        input::execute_with_input(self, input.into())
+    }
+
+    fn execute_lines<F>(self, keep: usize, on_line: F) -> Result<(), CommandError>
+    where
+        F: FnMut(Stream, &str),
+    {
+        lines::execute_lines(self, keep, on_line)
+    }
+
+    fn execute_tee(self, tee: Tee) -> Result<(), CommandError> {
+        tee::execute_tee(self, tee)
+    }
+
+    fn execute_timeout(self, timeout: Timeout) -> Result<(), CommandError> {
+        Pipeline::new(vec![self]).timeout(timeout).execute()
+    }
+
     fn scoped_spawn(self) -> Result<SpawnGuard, ::std::io::Error> {
-        let process = Process::new(self)?;
-        let id = process.id();
-        PID_MAP.lock().unwrap().insert(id, process);
-        Ok(SpawnGuard(id))
+        spawn_guard(self, None)
+    }
+
+    fn scoped_spawn_timeout(self, timeout: Timeout) -> Result<SpawnGuard, ::std::io::Error> {
+        let start = Instant::now();
+        let guard = spawn_guard(self, Some(timeout))?;
+        if let Some(ref process) = guard.process {
+            timeout::watch(Arc::downgrade(process), timeout, start, guard.timed_out.clone());
+        }
+        Ok(guard)
+    }
+}
+
+// Starts `command` for `scoped_spawn`. An executor runs it to completion
+// right away, within `timeout` if there is one.
+fn spawn_guard(command: Command, timeout: Option<Timeout>) -> Result<SpawnGuard, ::std::io::Error> {
+    let context = CommandContext::new(&command);
+    if dry_run::intercept(::std::slice::from_ref(&context)) {
+        return Ok(SpawnGuard::finished(context, ExitStatus::default()));
+    }
+    if let Some(executor) = executor::current() {
+        return match executor::run_one(&*executor, command, None, timeout) {
+            Ok(output) => Ok(SpawnGuard::finished(context, output.status)),
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::TimedOut && timeout.is_some() => {
+                let guard = SpawnGuard::finished(context, ExitStatus::default());
+                *guard.timed_out.lock().unwrap() = timeout.as_ref().map(Timeout::duration);
+                Ok(guard)
+            }
+            Err(err) => Err(err),
+        };
     }
+    Ok(SpawnGuard::new(Process::new(command)?, context))
 }
 
 //---------------
 
+#[derive(Debug, Clone)]
 pub enum CommandArg {
     Empty,
     Literal(String),
     List(Vec<String>),
+    /// A path or OS string, passed to the command without any conversion.
+    OsLiteral(OsString),
 }
 
 fn shell_quote(value: &str) -> String {
@@ -170,7 +565,7 @@ fn shell_quote(value: &str) -> String {
 
 impl fmt::Display for CommandArg {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use CommandArg::*;
+        use self::CommandArg::*;
         match *self {
             Empty => write!(f, ""),
             Literal(ref value) => {
@@ -183,11 +578,15 @@ impl fmt::Display for CommandArg {
                     .collect::<Vec<_>>()
                     .join(" "))
             }
+            // Only text can be quoted; invalid Unicode is replaced.
+            OsLiteral(ref value) => {
+                write!(f, "{}", shell_quote(&value.to_string_lossy()))
+            },
         }
     }
 }
 
-impl<'a, 'b> From<&'a &'b str> for CommandArg {
+impl From<&&str> for CommandArg {
     fn from(value: &&str) -> Self {
         CommandArg::Literal(value.to_string())
     }
@@ -199,44 +598,92 @@ impl From<String> for CommandArg {
     }
 }
 
-impl<'a> From<&'a String> for CommandArg {
+impl From<&String> for CommandArg {
     fn from(value: &String) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
 
-impl<'a> From<&'a str> for CommandArg {
+impl From<&str> for CommandArg {
     fn from(value: &str) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
-impl<'a> From<&'a u64> for CommandArg {
+impl From<&OsStr> for CommandArg {
+    fn from(value: &OsStr) -> Self {
+        CommandArg::OsLiteral(value.to_owned())
+    }
+}
+
+impl From<&&OsStr> for CommandArg {
+    fn from(value: &&OsStr) -> Self {
+        CommandArg::OsLiteral(value.to_os_string())
+    }
+}
+
+impl From<OsString> for CommandArg {
+    fn from(value: OsString) -> Self {
+        CommandArg::OsLiteral(value)
+    }
+}
+
+impl From<&OsString> for CommandArg {
+    fn from(value: &OsString) -> Self {
+        CommandArg::OsLiteral(value.clone())
+    }
+}
+
+impl From<&Path> for CommandArg {
+    fn from(value: &Path) -> Self {
+        CommandArg::OsLiteral(value.as_os_str().to_owned())
+    }
+}
+
+impl From<&&Path> for CommandArg {
+    fn from(value: &&Path) -> Self {
+        CommandArg::OsLiteral(value.as_os_str().to_owned())
+    }
+}
+
+impl From<PathBuf> for CommandArg {
+    fn from(value: PathBuf) -> Self {
+        CommandArg::OsLiteral(value.into_os_string())
+    }
+}
+
+impl From<&PathBuf> for CommandArg {
+    fn from(value: &PathBuf) -> Self {
+        CommandArg::OsLiteral(value.as_os_str().to_owned())
+    }
+}
+
+impl From<&u64> for CommandArg {
     fn from(value: &u64) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
-impl<'a> From<&'a f64> for CommandArg {
+impl From<&f64> for CommandArg {
     fn from(value: &f64) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
-impl<'a> From<&'a i32> for CommandArg {
+impl From<&i32> for CommandArg {
     fn from(value: &i32) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
-impl<'a> From<&'a i64> for CommandArg {
+impl From<&i64> for CommandArg {
     fn from(value: &i64) -> Self {
         CommandArg::Literal(value.to_string())
     }
 }
 
-impl<'a, T> From<&'a [T]> for CommandArg
+impl<T> From<&[T]> for CommandArg
     where T: fmt::Display {
     fn from(list: &[T]) -> Self {
         CommandArg::List(
@@ -248,14 +695,14 @@ impl<'a, T> From<&'a [T]> for CommandArg
     }
 }
 
-impl<'a, T> From<&'a Vec<T>> for CommandArg
+impl<T> From<&Vec<T>> for CommandArg
     where T: fmt::Display {
     fn from(list: &Vec<T>) -> Self {
         CommandArg::from(list.as_slice())
     }
 }
 
-impl<'a, T> From<&'a Option<T>> for CommandArg
+impl<T> From<&Option<T>> for CommandArg
     where T: fmt::Display {
     fn from(opt: &Option<T>) -> Self {
         if let Some(ref value) = *opt {
@@ -274,20 +721,84 @@ pub fn command_arg<'a, T>(value: &'a T) -> CommandArg
 //---------------
 
 /// Represents the invocation specification used to generate a Command.
-#[derive(Debug)]
-struct CommandSpec {
-    binary: String,
-    args: Vec<String>,
-    env: HashMap<String, String>,
-    cd: Option<String>,
+///
+/// Get one from `CommandSpec::parse` or from an existing `Command`, adjust
+/// its fields, and build the `Command` with `to_command`.
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    pub binary: OsString,
+    pub args: Vec<OsString>,
+    /// Variables set on top of the inherited environment.
+    pub env: HashMap<OsString, OsString>,
+    /// The working directory, which must exist when the `Command` is built.
+    pub cd: Option<PathBuf>,
+    /// How long the command may run. `to_pipeline` applies it, and
+    /// `to_command` rejects a spec that has one.
+    pub timeout: Option<Duration>,
+    redirects: Vec<Redirect>,
 }
 
 impl CommandSpec {
-    fn to_command(&self) -> Command {
+    /// Parses a spec the same way `commandify` does, but keeps the result
+    /// as a `CommandSpec`. A `timeout` line is allowed here.
+    pub fn parse<T: Into<Template>>(value: T) -> Result<CommandSpec, CommandError> {
+        let mut steps = parse_spec(value.into())?;
+        check!(steps.len() == 1, CommandError::UnexpectedSequence);
+        let mut specs = steps.remove(0).1;
+        check!(specs.len() == 1, CommandError::UnexpectedPipe);
+        Ok(specs.remove(0))
+    }
+
+    /// Renders the spec as a shell line that runs the same command, such as
+    /// `cd /src && env RUST_LOG=full cargo build --release > log`. Values are
+    /// quoted like `CommandArg`'s `Display` does, and a timeout becomes a
+    /// call to `timeout`. Heredoc bodies go on the lines after the command.
+    pub fn to_shell_string(&self) -> String {
+        let mut words = vec![];
+        if let Some(timeout) = self.timeout {
+            words.push(format!("timeout {}s", timeout.as_secs_f64()));
+        }
+        words.push(self.command_line());
+        words.extend(self.redirects.iter().map(Redirect::to_shell_string));
+        let env = self.env.iter().map(|(key, value)| (key.as_os_str(), Some(value.as_os_str())));
+        let mut line = context::shell_string(self.cd.as_deref(), env, &words.join(" "));
+        // Like in a shell, heredoc bodies follow the line, in order.
+        for lines in self.redirects.iter().filter_map(Redirect::heredoc_lines) {
+            line.push('\n');
+            line.push_str(&lines);
+        }
+        line
+    }
+
+    // The program and its arguments, quoted.
+    fn command_line(&self) -> String {
+        Some(&self.binary)
+            .into_iter()
+            .chain(&self.args)
+            .map(|arg| shell_quote(&arg.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Builds the `Command`. Fails if `cd` doesn't exist, or with
+    /// `UnexpectedTimeout` if the spec has a timeout, which a `Command` can't
+    /// carry. Redirected files are only opened once the command is spawned.
+    pub fn to_command(&self) -> Result<Command, CommandError> {
+        check!(self.timeout.is_none(), CommandError::UnexpectedTimeout);
+        self.to_stage().map(|(command, _)| command)
+    }
+
+    /// Builds a `Pipeline` of this one command, which also applies the
+    /// timeout, like `pipelinify`.
+    pub fn to_pipeline(&self) -> Result<Pipeline, CommandError> {
+        spec_pipeline(::std::slice::from_ref(self)).map_err(|(_, err)| err)
+    }
+
+    fn to_stage(&self) -> Result<(Command, StageIo), CommandError> {
         let cd = if let Some(ref cd) = self.cd {
-            canonicalize_path(Path::new(cd)).unwrap()
+            canonicalize_path(cd.as_path())?
         } else {
-            ::std::env::current_dir().unwrap()
+            ::std::env::current_dir().map_err(|err| CommandError::Io(err, None))?
         };
         let mut binary = Path::new(&self.binary).to_owned();
 
@@ -303,34 +814,63 @@ impl CommandSpec {
         // of accomplishing this and may contain errors.)
         if cfg!(windows) {
             let mut cmd = Command::new("cmd");
-            cmd.current_dir(cd);
-            let invoke_string = format!("{} {}", binary.as_path().to_string_lossy(), self.args.join(" "));
-            cmd.args(&["/C", &invoke_string]);
+            let mut invoke_string = binary.into_os_string();
+            for arg in &self.args {
+                invoke_string.push(" ");
+                invoke_string.push(arg);
+            }
+            cmd.arg("/C").arg(invoke_string);
             for (key, value) in &self.env {
                 cmd.env(key, value);
             }
-            return cmd;
+            let io = redirect::apply(&mut cmd, &self.redirects, &cd)?;
+            cmd.current_dir(cd);
+            return Ok((cmd, io));
         }
 
         let mut cmd = Command::new(binary);
-        cmd.current_dir(cd);
         cmd.args(&self.args);
         for (key, value) in &self.env {
             cmd.env(key, value);
         }
-        cmd
+        let io = redirect::apply(&mut cmd, &self.redirects, &cd)?;
+        cmd.current_dir(cd);
+        Ok((cmd, io))
+    }
+}
+
+/// Reads back what was set on a `Command`. Its stdio settings can't be
+/// read, so they are not part of the spec. Fails with `EnvRemoved` if the
+/// command removes an inherited variable, which a spec can't express.
+impl TryFrom<&Command> for CommandSpec {
+    type Error = CommandError;
+
+    fn try_from(command: &Command) -> Result<CommandSpec, CommandError> {
+        let mut env = HashMap::new();
+        for (key, value) in command.get_envs() {
+            let value = value.ok_or_else(|| CommandError::EnvRemoved(key.to_owned()))?;
+            env.insert(key.to_owned(), value.to_owned());
+        }
+        Ok(CommandSpec {
+            binary: command.get_program().to_owned(),
+            args: command.get_args().map(|arg| arg.to_owned()).collect(),
+            env,
+            cd: command.get_current_dir().map(|dir| dir.to_owned()),
+            timeout: None,
+            redirects: vec![],
+        })
     }
 }
 
 // Strips UNC from canonicalized paths.
 // See https://github.com/rust-lang/rust/issues/42869 for why this is needed.
 #[cfg(windows)]
-fn canonicalize_path<'p, P>(path: P) -> Result<PathBuf, Error>
+fn canonicalize_path<'p, P>(path: P) -> Result<PathBuf, CommandError>
 where P: Into<&'p Path> {
     use std::ffi::OsString;
     use std::os::windows::prelude::*;
 
-    let canonical = path.into().canonicalize()?;
+    let canonical = path.into().canonicalize().map_err(|err| CommandError::Io(err, None))?;
     let vec_chars = canonical.as_os_str().encode_wide().collect::<Vec<u16>>();
     if vec_chars[0..4] == [92, 92, 63, 92] {
         return Ok(Path::new(&OsString::from_wide(&vec_chars[4..])).to_owned());
@@ -342,13 +882,55 @@ where P: Into<&'p Path> {
 #[cfg(not(windows))]
 fn canonicalize_path<'p, P>(path: P) -> Result<PathBuf, CommandError>
 where P: Into<&'p Path> {
-    Ok(path.into().canonicalize().map_err(CommandError::Io)?)
+    path.into().canonicalize().map_err(|err| CommandError::Io(err, None))
 }
 
 //---------------
 
-pub fn commandify(value: &str) -> Result<Command, CommandError> {
-    let lines = value.trim().split('\n').map(String::from).collect::<Vec<_>>();
+// The sequence steps of a parsed spec, each holding one CommandSpec per pipeline stage.
+type Steps = Vec<(Connector, Vec<CommandSpec>)>;
+
+// Parses a spec into its sequence steps.
+fn parse_spec(value: Template) -> Result<Steps, CommandError> {
+    parse_spec_lines(value).map(|(steps, _)| steps).map_err(|(_, err)| err)
+}
+
+// Where the parts of a parsed spec are, as lines counted from 1, for errors
+// that only come up once the spec is built.
+#[derive(Debug, Default)]
+struct SpecLines {
+    cd: Option<usize>,
+    timeout: Option<usize>,
+    // The line of each stage, by step.
+    stages: Vec<Vec<usize>>,
+}
+
+impl SpecLines {
+    // The line to blame for `err` from building a stage: the `cd` line if
+    // the directory was the problem, otherwise the stage itself.
+    fn blame(&self, step: usize, stage: usize, err: &CommandError) -> Option<usize> {
+        match *err {
+            CommandError::Io(_, None) if self.cd.is_some() => self.cd,
+            _ => self.stages.get(step).and_then(|stages| stages.get(stage)).copied(),
+        }
+    }
+}
+
+// Like `parse_spec`, but also tells where things are. On failure, gives the
+// line the error was found on, if it is tied to one.
+fn parse_spec_lines(value: Template) -> Result<(Steps, SpecLines), (Option<usize>, CommandError)> {
+    let mut segments = value.into_segments();
+    // The lines trimmed off the start still count.
+    let mut offset = 0;
+    if let Some(Segment::Text(text)) = segments.first_mut() {
+        let trimmed = text.trim_start();
+        offset = text[..text.len() - trimmed.len()].matches('\n').count();
+        *text = trimmed.to_string();
+    }
+    if let Some(Segment::Text(text)) = segments.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+    let lines = split_lines(segments);
 
     #[derive(Debug, PartialEq)]
     enum SpecState {
@@ -357,68 +939,253 @@ pub fn commandify(value: &str) -> Result<Command, CommandError> {
         Cmd,
     }
 
-    let mut env = HashMap::<String, String>::new();
+    let mut env = HashMap::<OsString, OsString>::new();
     let mut cd = None;
+    let mut timeout = None;
+    let mut spec_lines = SpecLines::default();
 
     let mut state = SpecState::Cd;
     let mut command_lines = vec![];
-    for raw_line in lines {
-        let mut line = shlex::split(&raw_line).unwrap_or_default();
+    // The line the command section starts on.
+    let mut first_line = 0;
+    for (index, raw_line) in lines.into_iter().enumerate() {
+        let number = offset + index + 1;
         if state == SpecState::Cmd {
             command_lines.push(raw_line);
         } else {
-            if raw_line.trim().is_empty() {
+            let is_blank = raw_line.iter().all(|segment| match *segment {
+                Segment::Text(ref text) => text.trim().is_empty(),
+                Segment::Arg(_) => false,
+            });
+            if is_blank {
                 continue;
             }
 
-            match line.get(0).map(|x| x.as_ref()) {
+            // Only a line made of plain words can be part of the preamble.
+            // A line holding only a comment is skipped like a blank one.
+            let tokens = lexer::tokenize(&raw_line);
+            if tokens.as_ref().is_ok_and(|tokens| tokens.is_empty()) {
+                continue;
+            }
+            let mut line = vec![];
+            for token in tokens.unwrap_or_default() {
+                match token {
+                    Token::Word(word) => line.push(word),
+                    _ => {
+                        line.clear();
+                        break;
+                    }
+                }
+            }
+
+            match line.first().and_then(|x| x.to_str()) {
                 Some("cd") => {
                     if state != SpecState::Cd {
-                        return Err(CommandError::NoChangeDir);
+                        return Err((Some(number), CommandError::NoChangeDir));
                     }
-                    check!(line.len() == 2, CommandError::TooManyCDArgs(1,line.len() - 1));
-                    cd = Some(line.remove(1));
+                    check!(line.len() == 2, (Some(number), CommandError::TooManyCDArgs(1,line.len() - 1)));
+                    cd = Some(PathBuf::from(line.remove(1)));
+                    spec_lines.cd = Some(number);
                     state = SpecState::Env;
                 }
                 Some("export") => {
                     if state != SpecState::Cd && state != SpecState::Env {
-                        return Err(CommandError::ExportMispositioned);
+                        return Err((Some(number), CommandError::ExportMispositioned));
                     }
-                    check!(line.len() >= 2, CommandError::NotEnoughExportArgs(1,line.len() - 1));
+                    check!(line.len() >= 2, (Some(number), CommandError::NotEnoughExportArgs(1,line.len() - 1)));
                     for item in &line[1..] {
-                        let mut items = item.splitn(2, '=').collect::<Vec<_>>();
-                        check!(!items.is_empty(), CommandError::InvalidExport);
-                        env.insert(items[0].to_string(), items[1].to_string());
+                        let (key, value) =
+                            argv::split_export(item).ok_or((Some(number), CommandError::InvalidExport))?;
+                        env.insert(key, value);
                     }
                     state = SpecState::Env;
                 }
+                // `timeout 5 cmd` with more words is the command of the same name.
+                Some("timeout") if line.len() == 2 => {
+                    check!(timeout.is_none(), (Some(number), CommandError::InvalidTimeout));
+                    let duration = line[1].to_str().and_then(timeout::parse_duration);
+                    timeout = Some(duration.ok_or((Some(number), CommandError::InvalidTimeout))?);
+                    spec_lines.timeout = Some(number);
+                }
                 None | Some(_) => {
                     command_lines.push(raw_line);
+                    first_line = number;
                     state = SpecState::Cmd;
                 }
             }
         }
     }
     if state != SpecState::Cmd || command_lines.is_empty() {
-        return Err(CommandError::NoCommand);
+        return Err((None, CommandError::NoCommand));
     }
 
-    // Join the command string and split out binary / args.
-    let command_string = command_lines.join("\n").replace("\\\n", "\n");
-    let mut command = shlex::split(&command_string).expect("Command string couldn't be parsed by shlex");
-    let binary = command.remove(0); 
-    let args = command;
+    // Join the command lines and split them into sequence steps and pipeline stages.
+    let mut command = Template::new();
+    for (index, line) in command_lines.into_iter().enumerate() {
+        if index > 0 {
+            command = command.text("\n");
+        }
+        for segment in line {
+            command = match segment {
+                Segment::Text(text) => command.text(&text),
+                Segment::Arg(arg) => command.arg(arg),
+            };
+        }
+    }
+    let command = command
+        .into_segments()
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => Segment::Text(text.replace("\\\n", "\n")),
+            arg => arg,
+        })
+        .collect::<Vec<_>>();
+    let mut tokens = lexer::tokenize_lines(&command)
+        .map_err(|(line, err)| (Some(first_line + line), err))?
+        .into_iter();
+    // Each stage keeps the line of its first word. An empty one keeps the
+    // line of the operator that ends it, or else of the one that started it.
+    let mut steps = vec![(Connector::Then, vec![(first_line, vec![], vec![])])];
+    while let Some((line, token)) = tokens.next() {
+        let line = first_line + line;
+        let stages = &mut steps.last_mut().unwrap().1;
+        let (stage_line, words, redirects) = stages.last_mut().unwrap();
+        if words.is_empty() && matches!(token, Token::Pipe | Token::Connector(_)) {
+            *stage_line = line;
+        }
+        match token {
+            Token::Word(word) => {
+                if words.is_empty() {
+                    *stage_line = line;
+                }
+                words.push(word);
+            }
+            Token::Pipe => stages.push((line, vec![], vec![])),
+            Token::Connector(connector) => steps.push((connector, vec![(line, vec![], vec![])])),
+            Token::Heredoc(body) => redirects.push(Redirect::Heredoc(body)),
+            Token::Redirect(Redirection::Duplicate(from, to)) => {
+                redirects.push(Redirect::Duplicate(from, to));
+            }
+            Token::Redirect(redirection) => {
+                let path = match tokens.next() {
+                    Some((_, Token::Word(path))) if !path.is_empty() => path,
+                    _ => return Err((Some(line), CommandError::InvalidRedirect)),
+                };
+                redirects.push(match redirection {
+                    Redirection::Write(stream, append) => Redirect::Write(stream, path.into(), append),
+                    _ => Redirect::Read(path.into()),
+                });
+            }
+        }
+    }
 
-    // Generate the CommandSpec struct.
-    let spec = CommandSpec {
-        binary,
-        args,
-        env,
-        cd,
+    let is_blank = |stages: &Vec<(usize, Vec<OsString>, Vec<Redirect>)>| stages.len() == 1 && stages[0].1.is_empty();
+    // A trailing ; is allowed, like in a shell.
+    let trailing_then = match steps.last() {
+        Some((Connector::Then, stages)) => steps.len() > 1 && is_blank(stages),
+        _ => false,
     };
+    if trailing_then {
+        steps.pop();
+    }
+    if steps.len() == 1 && is_blank(&steps[0].1) {
+        return Err((None, CommandError::NoCommand));
+    }
+    if let Some((_, stages)) = steps.iter().find(|(_, stages)| is_blank(stages)) {
+        return Err((Some(stages[0].0), CommandError::EmptySequenceStep));
+    }
+    if let Some((line, _, _)) = steps.iter().flat_map(|(_, stages)| stages).find(|(_, words, _)| words.is_empty()) {
+        return Err((Some(*line), CommandError::EmptyPipeStage));
+    }
+    spec_lines.stages = steps
+        .iter()
+        .map(|(_, stages)| stages.iter().map(|(line, _, _)| *line).collect())
+        .collect();
+
+    // Generate a CommandSpec struct for each stage.
+    let steps = steps
+        .into_iter()
+        .map(|(connector, stages)| {
+            let specs = stages
+                .into_iter()
+                .map(|(_, mut command, redirects)| {
+                    let binary = command.remove(0);
+                    CommandSpec {
+                        binary,
+                        args: command,
+                        env: env.clone(),
+                        cd: cd.clone(),
+                        timeout,
+                        redirects,
+                    }
+                })
+                .collect();
+            (connector, specs)
+        })
+        .collect();
 
     // DEBUG
-    // eprintln!("COMMAND: {:?}", spec);
+    // eprintln!("COMMAND: {:?}", steps);
+
+    Ok((steps, spec_lines))
+}
+
+// Splits segments into lines at the newlines of their text.
+fn split_lines(segments: Vec<Segment>) -> Vec<Vec<Segment>> {
+    let mut lines = vec![vec![]];
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => {
+                for (index, part) in text.split('\n').enumerate() {
+                    if index > 0 {
+                        lines.push(vec![]);
+                    }
+                    if !part.is_empty() {
+                        lines.last_mut().unwrap().push(Segment::Text(part.to_string()));
+                    }
+                }
+            }
+            arg => lines.last_mut().unwrap().push(arg),
+        }
+    }
+    lines
+}
+
+fn build_pipeline(specs: &[CommandSpec]) -> Result<Pipeline, CommandError> {
+    build_stages(specs).map_err(|(_, err)| err)
+}
+
+// Like `build_pipeline`, but on failure also gives the index of the stage that failed.
+fn build_stages(specs: &[CommandSpec]) -> Result<Pipeline, (usize, CommandError)> {
+    let stages = specs
+        .iter()
+        .enumerate()
+        .map(|(index, spec)| spec.to_stage().map_err(|err| (index, err)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(pipeline::with_redirects(stages))
+}
+
+// The pipeline of `pipelinify`, which takes its timeout from the spec.
+fn spec_pipeline(specs: &[CommandSpec]) -> Result<Pipeline, (usize, CommandError)> {
+    let pipeline = build_stages(specs)?;
+    Ok(match specs[0].timeout {
+        Some(duration) => pipeline.timeout(Timeout::new(duration)),
+        None => pipeline,
+    })
+}
+
+pub fn commandify<T: Into<Template>>(value: T) -> Result<Command, CommandError> {
+    CommandSpec::parse(value)?.to_command()
+}
+
+/// Like `commandify`, but also accepts `a | b | c` in the command section.
+pub fn pipelinify<T: Into<Template>>(value: T) -> Result<Pipeline, CommandError> {
+    let mut steps = parse_spec(value.into())?;
+    check!(steps.len() == 1, CommandError::UnexpectedSequence);
+    spec_pipeline(&steps.remove(0).1).map_err(|(_, err)| err)
+}
 
-    Ok(spec.to_command())
+/// Like `pipelinify`, but also accepts pipelines joined by `&&`, `||` and `;`.
+pub fn sequencify<T: Into<Template>>(value: T) -> Result<Sequence, CommandError> {
+    parse_spec(value.into()).map(sequence::from_specs)
 }
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+
+use super::{dry_run, executor, exit_status_result, io_error, CommandContext, CommandError};
+use crate::redirect::Stream;
+
+/// Runs `command` with both streams piped, calling `on_line` for every line
+/// as it arrives. Keeps the last `keep` lines of stderr for the error.
+pub fn execute_lines<F>(mut command: Command, keep: usize, mut on_line: F) -> Result<(), CommandError>
+where
+    F: FnMut(Stream, &str),
+{
+    let mut context = CommandContext::new(&command);
+    if dry_run::intercept(::std::slice::from_ref(&context)) {
+        return Ok(());
+    }
+    if let Some(executor) = executor::current() {
+        // The output is only there once the command finished, stdout first.
+        let output = executor::run_one(&*executor, command, None, None).map_err(io_error(&context))?;
+        for (stream, bytes) in [(Stream::Stdout, &output.stdout), (Stream::Stderr, &output.stderr)] {
+            for line in String::from_utf8_lossy(bytes).lines() {
+                on_line(stream, line);
+            }
+        }
+        let lines = String::from_utf8_lossy(&output.stderr).lines().map(str::to_string).collect::<Vec<_>>();
+        if keep > 0 && !lines.is_empty() {
+            context.stderr_tail = Some(lines[lines.len().saturating_sub(keep)..].join("\n"));
+        }
+        return exit_status_result(output.status, context);
+    }
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io_error(&context))?;
+
+    // Both streams are read on their own threads so neither can fill up and
+    // block the command, and the lines are handed back here in arrival order.
+    let (sender, receiver) = mpsc::channel();
+    let readers = vec![
+        read_stream(child.stdout.take().unwrap(), Stream::Stdout, true, sender.clone()),
+        read_stream(child.stderr.take().unwrap(), Stream::Stderr, true, sender),
+    ];
+
+    let mut tail = VecDeque::with_capacity(keep);
+    for (stream, line) in receiver {
+        // Invalid UTF-8 is replaced.
+        let line = String::from_utf8_lossy(&line).into_owned();
+        on_line(stream, &line);
+        if stream == Stream::Stderr && keep > 0 {
+            if tail.len() == keep {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+    }
+    // Reap the command before reporting a read error, so it isn't left a zombie.
+    let read = readers.into_iter().map(|reader| reader.join().unwrap()).collect::<Result<Vec<_>, _>>();
+    let status = child.wait().map_err(io_error(&context))?;
+    read.map_err(io_error(&context))?;
+    if !tail.is_empty() {
+        context.stderr_tail = Some(Vec::from(tail).join("\n"));
+    }
+    exit_status_result(status, context)
+}
+
+/// Reads `stream` on a new thread and sends what it reads, tagged with
+/// `tag`. With `lines` set, sends one line at a time without its line ending;
+/// otherwise sends chunks as soon as they arrive.
+pub(crate) fn read_stream<R: Read + Send + 'static>(
+    stream: R,
+    tag: Stream,
+    lines: bool,
+    sender: mpsc::Sender<(Stream, Vec<u8>)>,
+) -> thread::JoinHandle<::std::io::Result<()>> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut buf = vec![];
+            if lines {
+                if reader.read_until(b'\n', &mut buf)? == 0 {
+                    return Ok(());
+                }
+                if buf.ends_with(b"\n") {
+                    buf.pop();
+                    if buf.ends_with(b"\r") {
+                        buf.pop();
+                    }
+                }
+            } else {
+                buf.extend_from_slice(reader.fill_buf()?);
+                if buf.is_empty() {
+                    return Ok(());
+                }
+                reader.consume(buf.len());
+            }
+            // The receiver only goes away if the caller panicked.
+            let _ = sender.send((tag, buf));
+        }
+    })
+}
@@ -1,76 +1,93 @@
+// Templates accept the same arguments as `format!`: positional values,
+// `name = value` pairs, and variables captured from the surrounding scope.
+// `__command_template!` routes every value through `command_arg` and keeps it
+// apart from the literal text, so it is never quoted and split again.
+// `command!` goes through `__checked_template!`, which also rejects templates
+// that can never build a `Command`.
+//
+// The proc macros get `crate = $crate` first, so the code they generate works
+// when the crate is renamed, and every argument as an `expr`, so the commas
+// in `HashMap::<K, V>::new()` don't split it.
 
 #[macro_export]
 macro_rules! command {
-    ($fmt:expr) => ( command!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
+    ($template:expr $(, $args:expr)* $(,)?) => (
         {
             $crate::commandify(
-                &format!($fmt, $( $id = $crate::command_arg(&$value) ),*)
+                $crate::__checked_template!(crate = $crate, $template $(, $args)*)
             )
         }
     );
 }
 
+#[macro_export]
+macro_rules! pipeline {
+    ($template:expr $(, $args:expr)* $(,)?) => (
+        {
+            $crate::pipelinify(
+                $crate::__command_template!(crate = $crate, $template $(, $args)*)
+            )
+        }
+    );
+}
+
+#[macro_export]
+macro_rules! sequence {
+    ($template:expr $(, $args:expr)* $(,)?) => (
+        {
+            $crate::sequencify(
+                $crate::__command_template!(crate = $crate, $template $(, $args)*)
+            )
+        }
+    );
+}
 
 #[macro_export]
 macro_rules! execute {
-    ($fmt:expr) => ( execute!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
+    ($($args:tt)*) => (
         {
-            use $crate::{CommandSpecExt};
-            command!($fmt, $( $id = $value ),*).unwrap().execute()
+            $crate::sequence!($($args)*).and_then(|sequence| sequence.execute())
         }
     );
 }
 
 #[macro_export]
 macro_rules! sh_command {
-    ($fmt:expr) => ( sh_command!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
-        $crate::commandify(
-            &format!(
-                "sh -c {}",
-                $crate::command_arg(
-                    &format!("set -e\n\n{}", format!($fmt, $( $id = $crate::command_arg(&$value) ,)*)),
-                ),
-            )
-        )
+    ($template:expr $(, $args:expr)* $(,)?) => (
+        $crate::Shell::current().command(&$crate::__command_format!(crate = $crate, $template $(, $args)*))
     );
 }
 
 #[macro_export]
 macro_rules! sush_command {
-    ($fmt:expr) => ( sush_command!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
-        $crate::commandify(
-            &format!(
-                "pkexec sh -c {}",
-                $crate::command_arg(
-                    &format!("set -e\n\n{}", format!($fmt, $( $id = $crate::command_arg(&$value) ,)*)),
-                ),
-            )
-        )
+    ($template:expr $(, $args:expr)* $(,)?) => (
+        $crate::Shell::current().pkexec_command(&$crate::__command_format!(crate = $crate, $template $(, $args)*))
     );
 }
 
 #[macro_export]
 macro_rules! sh_execute {
-    ($fmt:expr) => ( sh_execute!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
-        {
-            use $crate::{CommandSpecExt};
-            sh_command!($fmt, $( $id = $value ),*).unwrap().execute()
-        }
+    ($($args:tt)*) => (
+        $crate::CommandSpecExt::execute($crate::sh_command!($($args)*).unwrap())
     );
 }
 
 #[macro_export]
 macro_rules! sush_execute {
-    ($fmt:expr) => ( sush_execute!($fmt,) );
-    ($fmt:expr, $( $id:ident = $value:expr ),* $(,)*) => (
-        {
-            use $crate::{CommandSpecExt};
-            sush_command!($fmt, $( $id = $value ),*).unwrap().execute()
-        }
+    ($($args:tt)*) => (
+        $crate::CommandSpecExt::execute($crate::sush_command!($($args)*).unwrap())
+    );
+}
+
+/// Like `command!`, but the template is parsed while compiling.
+///
+/// Placeholders work as in `command!`. Malformed preambles, unbalanced quotes
+/// and unknown or unused arguments are reported as compiler errors. The
+/// expansion builds the `Command` directly from the parsed words, so values
+/// are never quoted and split again.
+#[macro_export]
+macro_rules! checked_command {
+    ($template:expr $(, $args:expr)* $(,)?) => (
+        $crate::__checked_command!(crate = $crate, $template $(, $args)*)
     );
-}
\ No newline at end of file
+}
@@ -0,0 +1,397 @@
+use std::io;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Instant;
+#[cfg(feature = "serde")]
+use std::{io::Read, process::Output, thread};
+
+use super::{dry_run, executor, exit_status_result, io_error, register, unregister, CommandContext, CommandError};
+#[cfg(feature = "serde")]
+use super::{input, Input};
+#[cfg(feature = "serde")]
+use crate::process::Pipes;
+use crate::process::Process;
+use crate::redirect::StageIo;
+use crate::timeout::{self, Outcome, Timeout};
+
+/// A chain of commands whose stdout is wired to the stdin of the next one,
+/// like `a | b | c` in a shell.
+#[derive(Debug)]
+pub struct Pipeline {
+    stages: Vec<Command>,
+    io: Vec<StageIo>,
+    timeout: Option<Timeout>,
+}
+
+// Builds a pipeline whose stages already had their redirects applied.
+pub fn with_redirects(stages: Vec<(Command, StageIo)>) -> Pipeline {
+    let (stages, io) = stages.into_iter().unzip();
+    Pipeline { stages, io, timeout: None }
+}
+
+// A started stage; with a timeout it runs in its own process group, which
+// is registered for `cleanup_on_ctrlc` until the stage was waited for.
+enum Running {
+    Child(Child),
+    Process(Arc<Process>),
+}
+
+impl Running {
+    #[cfg(feature = "serde")]
+    fn take_pipes(&mut self) -> Pipes {
+        match *self {
+            Running::Child(ref mut child) => (child.stdin.take(), child.stdout.take(), child.stderr.take()),
+            Running::Process(ref process) => process.take_pipes(),
+        }
+    }
+
+    fn wait(self) -> io::Result<ExitStatus> {
+        match self {
+            Running::Child(mut child) => child.wait(),
+            Running::Process(process) => {
+                let status = process.status();
+                unregister(&process);
+                status
+            }
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Command>) -> Pipeline {
+        let io = vec![StageIo::default(); stages.len()];
+        Pipeline { stages, io, timeout: None }
+    }
+
+    /// Limits how long the pipeline may run. Each stage then runs in its own
+    /// process group, which is signalled when the time is up.
+    pub fn timeout(mut self, timeout: Timeout) -> Pipeline {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn stages(&self) -> &[Command] {
+        &self.stages
+    }
+
+    pub fn stages_mut(&mut self) -> &mut [Command] {
+        &mut self.stages
+    }
+
+    pub fn into_stages(self) -> Vec<Command> {
+        self.stages
+    }
+
+    /// Runs all stages to completion and returns the exit status of each one, in order.
+    pub fn status(self) -> Result<Vec<ExitStatus>, CommandError> {
+        self.run().map(|stages| stages.into_iter().map(|(status, _)| status).collect())
+    }
+
+    // Like `status`, but also describes each stage for error reporting.
+    fn run(self) -> Result<Vec<(ExitStatus, CommandContext)>, CommandError> {
+        match self.spawn()? {
+            Spawned::Finished(stages) => Ok(stages),
+            Spawned::Running(running) => running.wait(),
+        }
+    }
+
+    // Starts all stages. A dry run or an executor finishes them right away.
+    fn spawn(self) -> Result<Spawned, CommandError> {
+        let count = self.stages.len();
+        let contexts = self.stages.iter().map(CommandContext::new).collect::<Vec<_>>();
+        let redirects = self.io.iter().map(|io| &io.redirects[..]).collect::<Vec<_>>();
+        if dry_run::intercept_pipeline(&contexts, &redirects) {
+            return Ok(Spawned::Finished(
+                contexts.into_iter().map(|context| (ExitStatus::default(), context)).collect(),
+            ));
+        }
+        if let Some(executor) = executor::current() {
+            let timeout = self.timeout;
+            // An error can't be pinned on a stage, so it gets the first one.
+            let error = |err: io::Error| match (timeout, contexts.first()) {
+                (Some(timeout), Some(context)) if err.kind() == io::ErrorKind::TimedOut => {
+                    CommandError::Timeout(timeout.duration(), Box::new(context.clone()))
+                }
+                _ => CommandError::Io(err, contexts.first().cloned().map(Box::new)),
+            };
+            let outputs = executor.run(self.stages, None, timeout).map_err(error)?;
+            if outputs.len() != count {
+                return Err(error(executor::wrong_count(count, outputs.len())));
+            }
+            return Ok(Spawned::Finished(
+                outputs.into_iter().map(|output| output.status).zip(contexts).collect(),
+            ));
+        }
+        self.start(contexts, |_, _| {}).map(Spawned::Running)
+    }
+
+    // Runs the stages, bypassing a dry run or an executor, with `input` fed
+    // to the first one. Captures the stdout of the last one and the stderr
+    // of each, like `Command::output`. Used by `Recorder`.
+    #[cfg(feature = "serde")]
+    pub(crate) fn output(self, input: Option<Input>) -> Result<Vec<Output>, CommandError> {
+        let contexts = self.stages.iter().map(CommandContext::new).collect::<Vec<_>>();
+        let count = contexts.len();
+        let feed = input.is_some();
+        let mut stages = self.start(contexts, |index, command| {
+            command.stderr(Stdio::piped());
+            if index + 1 == count {
+                command.stdout(Stdio::piped());
+            }
+            if index == 0 && feed {
+                command.stdin(Stdio::piped());
+            }
+        })?;
+
+        let mut pipes = stages.children.iter_mut().map(Running::take_pipes).collect::<Vec<_>>();
+        let writer = match (input, pipes.first_mut().and_then(|pipes| pipes.0.take())) {
+            (Some(input), Some(stdin)) => Some(input::feed(input, stdin)),
+            _ => None,
+        };
+        let stdout = pipes.last_mut().and_then(|pipes| pipes.1.take()).map(read_all);
+        let stderr = pipes.into_iter().map(|pipes| pipes.2.map(read_all)).collect::<Vec<_>>();
+
+        let mut outputs = vec![];
+        for ((status, context), stderr) in stages.wait()?.into_iter().zip(stderr) {
+            let stderr = finish(stderr).map_err(io_error(&context))?;
+            outputs.push(Output { status, stdout: vec![], stderr });
+        }
+        if let Some(last) = outputs.last_mut() {
+            last.stdout = finish(stdout).map_err(|err| CommandError::Io(err, None))?;
+        }
+        if let Some(writer) = writer {
+            writer.join().unwrap().map_err(|err| CommandError::Io(err, None))?;
+        }
+        Ok(outputs)
+    }
+
+    // Starts all stages, described by `contexts`. `setup` gets each stage
+    // with its index before it is connected to the others.
+    fn start<F>(self, contexts: Vec<CommandContext>, setup: F) -> Result<Stages, CommandError>
+    where
+        F: Fn(usize, &mut Command),
+    {
+        let count = self.stages.len();
+        let timeout = self.timeout;
+        let start = Instant::now();
+        let mut children = vec![];
+        let mut previous_stdout = None;
+
+        for (index, ((mut command, io), context)) in self.stages.into_iter().zip(self.io).zip(&contexts).enumerate() {
+            setup(index, &mut command);
+            // The command is dropped right after spawning, which closes our
+            // copies of the pipe ends it was given.
+            let spawned = wire(&mut command, &io, previous_stdout.take(), index + 1 < count)
+                .and_then(|next_stdin| {
+                    previous_stdout = next_stdin;
+                    match timeout {
+                        Some(_) => Process::new(command).map(|process| Running::Process(register(process))),
+                        None => command.spawn().map(Running::Child),
+                    }
+                });
+
+            match spawned {
+                Ok(child) => children.push(child),
+                Err(err) => {
+                    // Don't leave the stages that already started behind as zombies.
+                    drop(previous_stdout);
+                    for child in children {
+                        let _ = child.wait();
+                    }
+                    // The child only reports an errno, so find out whether a
+                    // redirect was the problem.
+                    return Err(io.blame().unwrap_or_else(|| io_error(context)(err)));
+                }
+            }
+        }
+
+        Ok(Stages { children, contexts, timeout, start })
+    }
+
+    /// Runs the pipeline and fails if any stage failed, like `set -o pipefail`.
+    /// The rightmost failing stage is reported. An earlier stage killed by
+    /// SIGPIPE only stopped because a later one quit reading, so it doesn't count.
+    pub fn execute(self) -> Result<(), CommandError> {
+        pipefail(self.run()?)
+    }
+
+    /// Like `status`, but waits for the stages on a blocking task of the tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn status_async(self) -> Result<Vec<ExitStatus>, CommandError> {
+        let stages = self.run_async().await?;
+        Ok(stages.into_iter().map(|(status, _)| status).collect())
+    }
+
+    /// Like `execute`, but waits for the stages on a blocking task of the tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn execute_async(self) -> Result<(), CommandError> {
+        pipefail(self.run_async().await?)
+    }
+
+    // The stages are started here, where a dry run or an executor of this
+    // thread still applies; only the waiting moves to another thread.
+    #[cfg(feature = "tokio")]
+    async fn run_async(self) -> Result<Vec<(ExitStatus, CommandContext)>, CommandError> {
+        match self.spawn()? {
+            Spawned::Finished(stages) => Ok(stages),
+            Spawned::Running(running) => tokio::task::spawn_blocking(move || running.wait())
+                .await
+                .map_err(|err| CommandError::Io(io::Error::other(err), None))?,
+        }
+    }
+}
+
+// Reads all of `reader` on a new thread, so no pipe can fill up and block a stage.
+#[cfg(feature = "serde")]
+fn read_all<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map(|_| bytes)
+    })
+}
+
+// What a `read_all` thread read, or nothing if there was no pipe to read.
+#[cfg(feature = "serde")]
+fn finish(reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+    reader.map_or(Ok(vec![]), |reader| reader.join().unwrap())
+}
+
+// The outcome of `Pipeline::spawn`.
+enum Spawned {
+    Finished(Vec<(ExitStatus, CommandContext)>),
+    Running(Stages),
+}
+
+// Stages that were started and still have to be waited for.
+struct Stages {
+    children: Vec<Running>,
+    contexts: Vec<CommandContext>,
+    timeout: Option<Timeout>,
+    start: Instant,
+}
+
+impl Stages {
+    fn wait(self) -> Result<Vec<(ExitStatus, CommandContext)>, CommandError> {
+        let Stages { children, mut contexts, timeout, start } = self;
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                return children
+                    .into_iter()
+                    .zip(contexts)
+                    .map(|(child, context)| child.wait().map_err(io_error(&context)).map(|status| (status, context)))
+                    .collect();
+            }
+        };
+
+        let processes = children
+            .iter()
+            .filter_map(|child| match *child {
+                Running::Process(ref process) => Some(&**process),
+                Running::Child(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let outcome = timeout::wait(&processes, &timeout, start);
+        for process in processes {
+            unregister(process);
+        }
+        match outcome {
+            Ok(Outcome::Exited(statuses)) => Ok(statuses.into_iter().zip(contexts).collect()),
+            Ok(Outcome::TimedOut(elapsed, index)) => {
+                Err(CommandError::Timeout(elapsed, Box::new(contexts.swap_remove(index))))
+            }
+            Err(err) => Err(CommandError::Io(err, None)),
+        }
+    }
+}
+
+// Fails if any stage failed; see `Pipeline::execute`.
+fn pipefail(stages: Vec<(ExitStatus, CommandContext)>) -> Result<(), CommandError> {
+    let last = stages.len().saturating_sub(1);
+    for (index, (status, context)) in stages.into_iter().enumerate().rev() {
+        if let Err(err) = exit_status_result(status, context) {
+            if index != last && killed_by_sigpipe(status) {
+                continue;
+            }
+            if last == 0 {
+                return Err(err);
+            }
+            return Err(CommandError::Stage(index, Box::new(err)));
+        }
+    }
+    Ok(())
+}
+
+// Connects a stage to the previous one and, unless it is the last stage,
+// returns the read end of the pipe that the next stage should use as stdin.
+fn wire(
+    command: &mut Command,
+    io: &StageIo,
+    stdin: Option<io::PipeReader>,
+    piped: bool,
+) -> io::Result<Option<io::PipeReader>> {
+    if let Some(stdin) = stdin {
+        // With `< file` the stage never reads the pipe, so it is just dropped.
+        if !io.stdin_redirected {
+            command.stdin(Stdio::from(stdin));
+        }
+    }
+    if !piped {
+        return Ok(None);
+    }
+
+    let (reader, writer) = io::pipe()?;
+    if io.stderr_to_stdout {
+        command.stderr(Stdio::from(writer.try_clone()?));
+    }
+    // With `> file` the next stage reads an empty pipe, like in a shell.
+    if !io.stdout_redirected {
+        command.stdout(Stdio::from(writer));
+    }
+    Ok(Some(reader))
+}
+
+#[cfg(unix)]
+fn killed_by_sigpipe(status: ExitStatus) -> bool {
+    use nix::libc::SIGPIPE;
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal() == Some(SIGPIPE)
+}
+
+#[cfg(not(unix))]
+fn killed_by_sigpipe(_status: ExitStatus) -> bool {
+    false
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod tests {
+    use super::{Pipeline, Running, Spawned};
+    use crate::timeout::Timeout;
+    use crate::PID_MAP;
+    use std::process::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn timed_stages_are_registered() {
+        let mut sleep = Command::new("sleep");
+        sleep.arg("0.1");
+        let pipeline = Pipeline::new(vec![sleep, Command::new("true")]).timeout(Timeout::new(Duration::from_secs(5)));
+        let stages = match pipeline.spawn().unwrap() {
+            Spawned::Running(stages) => stages,
+            Spawned::Finished(_) => panic!("expected the stages to run"),
+        };
+        let pids = stages
+            .children
+            .iter()
+            .map(|child| match *child {
+                Running::Process(ref process) => process.id(),
+                Running::Child(_) => panic!("expected a process group"),
+            })
+            .collect::<Vec<_>>();
+        assert!(pids.iter().all(|pid| PID_MAP.lock().unwrap().contains_key(pid)));
+        stages.wait().unwrap();
+        assert!(!pids.iter().any(|pid| PID_MAP.lock().unwrap().contains_key(pid)));
+    }
+}
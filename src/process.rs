@@ -4,6 +4,7 @@
 // use pathop::PathOp;
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::process::{ChildStderr, ChildStdin, ChildStdout};
 
 // pub fn spawn(cmd: &Vec<String>, updated_paths: Vec<PathOp>, no_shell: bool) -> Process {
 //     self::imp::Process::new(cmd, updated_paths, no_shell).expect("unable to spawn process")
@@ -11,6 +12,11 @@ use std::path::PathBuf;
 
 pub use self::imp::Process;
 
+/// The ends of the pipes to a command's stdin, stdout and stderr, where they were piped.
+pub type Pipes = (Option<ChildStdin>, Option<ChildStdout>, Option<ChildStderr>);
+#[cfg(target_family = "unix")]
+pub use self::imp::{set_process_group, signal_group};
+
 /*
 fn needs_wrapping(s: &String) -> bool {
     s.contains(|ch| match ch {
@@ -62,13 +68,19 @@ mod imp {
     use nix::libc::*;
     use nix::{self, Error};
     // use pathop::PathOp;
-    use signal::Signal;
+    use crate::signal::Signal;
     use std::io::{self, Result};
-    use std::process::Command;
+    use super::Pipes;
+    use std::process::{Child, Command, ExitStatus};
     use std::sync::*;
 
     pub struct Process {
         pgid: pid_t,
+        child: Mutex<Child>,
+        // Set once the leader was reaped. The group id still can't be reused
+        // while any member of the group is alive, so the group can still be
+        // signalled; with no member left that just fails with ESRCH.
+        reaped: atomic::AtomicBool,
         lock: Mutex<bool>,
         cvar: Condvar,
     }
@@ -77,7 +89,57 @@ mod imp {
         match err {
             Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
             Error::InvalidPath => io::Error::new(io::ErrorKind::InvalidInput, err),
-            _ => io::Error::new(io::ErrorKind::Other, err),
+            _ => io::Error::other(err),
+        }
+    }
+
+    /// Makes the command the leader of a new process group once it is spawned.
+    pub fn set_process_group(command: &mut Command) {
+        use nix::unistd::*;
+        use std::os::unix::process::CommandExt;
+
+        unsafe {
+            command.pre_exec(|| setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(from_nix_error));
+        }
+    }
+
+    // Whether the leader `pid` has exited, without reaping it. With `WNOHANG`
+    // in `options` this doesn't block.
+    fn peek_exit(pid: pid_t, options: c_int) -> Result<bool> {
+        loop {
+            let mut info: siginfo_t = unsafe { ::std::mem::zeroed() };
+            if unsafe { waitid(P_PID, pid as id_t, &mut info, WEXITED | WNOWAIT | options) } == 0 {
+                return Ok(si_pid(&info) != 0);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn si_pid(info: &siginfo_t) -> pid_t {
+        unsafe { info.si_pid() }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn si_pid(info: &siginfo_t) -> pid_t {
+        info.si_pid
+    }
+
+    /// Sends `signal` to every process in the group `pgid`.
+    pub fn signal_group(pgid: pid_t, signal: Signal) {
+        use crate::signal::ConvertToLibc;
+
+        extern "C" {
+            fn killpg(pgrp: pid_t, sig: c_int) -> c_int;
+        }
+
+        let signo = signal.convert_to_libc();
+        debug!("Sending {:?} (int: {}) to child process", signal, signo);
+        unsafe {
+            killpg(pgid, signo);
         }
     }
 
@@ -88,42 +150,35 @@ mod imp {
         pub fn new(
             mut command: Command,
         ) -> Result<Process> {
-            use nix::unistd::*;
-            use std::os::unix::process::CommandExt;
-
+            set_process_group(&mut command);
             command
-                .before_exec(|| setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(from_nix_error))
                 .spawn()
-                .and_then(|p| {
-                    Ok(Process {
-                        pgid: p.id() as i32,
-                        lock: Mutex::new(false),
-                        cvar: Condvar::new(),
-                    })
+                .map(|p| Process {
+                    pgid: p.id() as i32,
+                    child: Mutex::new(p),
+                    reaped: atomic::AtomicBool::new(false),
+                    lock: Mutex::new(false),
+                    cvar: Condvar::new(),
                 })
         }
 
+        /// Takes the pipes that `Stdio::piped()` set up for the command.
+        pub fn take_pipes(&self) -> Pipes {
+            let mut child = self.child.lock().unwrap();
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        }
+
         pub fn id(&self) -> i32 {
-            self.pgid as i32
+            self.pgid
         }
 
         pub fn reap(&self) {
-            use nix::sys::wait::*;
-            use nix::unistd::Pid;
-
-            let mut finished = true;
-            loop {
-                match waitpid(Pid::from_raw(-self.pgid), Some(WaitPidFlag::WNOHANG)) {
-                    Ok(WaitStatus::Exited(_, _)) | Ok(WaitStatus::Signaled(_, _, _)) => {
-                        finished = finished
-                    }
-                    Ok(_) => {
-                        finished = false;
-                        break;
-                    }
-                    Err(_) => break,
-                }
-            }
+            // The group leader is the only member that is our own child. Reaping
+            // it through the `Child` keeps its exit status around for `try_status`.
+            let finished = match self.try_status() {
+                Ok(Some(_)) | Err(_) => true,
+                Ok(None) => false,
+            };
 
             if finished {
                 let mut done = self.lock.lock().unwrap();
@@ -132,22 +187,25 @@ mod imp {
             }
         }
 
-        pub fn signal(&self, signal: Signal) {
-            use signal::ConvertToLibc;
-
-            let signo = signal.convert_to_libc();
-            debug!("Sending {:?} (int: {}) to child process", signal, signo);
-            self.c_signal(signo);
+        /// Signals the group, which still reaches what the command left
+        /// running after its leader was reaped. Returns whether the leader
+        /// was still running.
+        pub fn signal(&self, signal: Signal) -> bool {
+            // Holding the child keeps it from being reaped meanwhile.
+            let _child = self.child.lock().unwrap();
+            let running = !self.reaped.load(atomic::Ordering::SeqCst) && !peek_exit(self.pgid, WNOHANG).unwrap_or(true);
+            signal_group(self.pgid, signal);
+            running
         }
 
-        fn c_signal(&self, sig: c_int) {
-            extern "C" {
-                fn killpg(pgrp: pid_t, sig: c_int) -> c_int;
-            }
-
-            unsafe {
-                killpg(self.pgid, sig);
+        /// Whether the group leader has exited. Unlike `try_status`, this
+        /// leaves it unreaped, so the group can still be signalled.
+        pub fn exited(&self) -> Result<bool> {
+            let _child = self.child.lock().unwrap();
+            if self.reaped.load(atomic::Ordering::SeqCst) {
+                return Ok(true);
             }
+            peek_exit(self.pgid, WNOHANG)
         }
 
         pub fn wait(&self) {
@@ -156,6 +214,34 @@ mod imp {
                 done = self.cvar.wait(done).unwrap();
             }
         }
+
+        /// The exit status of the group leader, if it has exited. Reaps it.
+        pub fn try_status(&self) -> Result<Option<ExitStatus>> {
+            let mut child = self.child.lock().unwrap();
+            let status = child.try_wait()?;
+            if status.is_some() {
+                self.reaped.store(true, atomic::Ordering::SeqCst);
+            }
+            Ok(status)
+        }
+
+        /// Waits for the group leader to exit, and reaps it.
+        pub fn status(&self) -> Result<ExitStatus> {
+            // Block without holding the child, so the group can still be signalled meanwhile.
+            if !self.reaped.load(atomic::Ordering::SeqCst) {
+                peek_exit(self.pgid, 0)?;
+            }
+            let status = self.child.lock().unwrap().wait()?;
+            self.reaped.store(true, atomic::Ordering::SeqCst);
+            Ok(status)
+        }
+    }
+
+    impl Drop for Process {
+        fn drop(&mut self) {
+            // Don't leave a leader that exited behind as a zombie.
+            let _ = self.try_status();
+        }
     }
 }
 
@@ -164,17 +250,20 @@ mod imp {
     //use super::wrap_commands;
     use kernel32::*;
     // use pathop::PathOp;
-    use signal::Signal;
+    use crate::signal::Signal;
     use std::io;
     use std::io::Result;
     use std::mem;
-    use std::process::Command;
+    use super::Pipes;
+    use std::process::{Child, Command, ExitStatus};
     use std::ptr;
+    use std::sync::Mutex;
     use winapi::*;
 
     pub struct Process {
         job: HANDLE,
         completion_port: HANDLE,
+        child: Mutex<Child>,
     }
 
     #[repr(C)]
@@ -187,7 +276,7 @@ mod imp {
         pub fn new(
             mut command: Command,
         ) -> Result<Process> {
-            use std::os::windows::io::IntoRawHandle;
+            use std::os::windows::io::AsRawHandle;
             use std::os::windows::process::CommandExt;
 
             fn last_err() -> io::Error {
@@ -243,7 +332,7 @@ mod imp {
 
             command.creation_flags(CREATE_SUSPENDED);
             command.spawn().and_then(|p| {
-                let handle = p.into_raw_handle();
+                let handle = p.as_raw_handle();
                 let r = unsafe { AssignProcessToJobObject(job, handle) };
                 if r == 0 {
                     panic!("failed to add to job object: {}", last_err());
@@ -254,20 +343,33 @@ mod imp {
                 Ok(Process {
                     job: job,
                     completion_port: completion_port,
+                    child: Mutex::new(p),
                 })
             })
         }
 
+        /// Takes the pipes that `Stdio::piped()` set up for the command.
+        pub fn take_pipes(&self) -> Pipes {
+            let mut child = self.child.lock().unwrap();
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        }
+
         pub fn id(&self) -> i32 {
             self.job as i32
         }
 
         pub fn reap(&self) {}
 
-        pub fn signal(&self, _signal: Signal) {
+        pub fn signal(&self, _signal: Signal) -> bool {
+            let running = self.exited().map_or(false, |exited| !exited);
             unsafe {
                 let _ = TerminateJobObject(self.job, 1);
             }
+            running
+        }
+
+        pub fn exited(&self) -> Result<bool> {
+            self.try_status().map(|status| status.is_some())
         }
 
         pub fn wait(&self) {
@@ -290,6 +392,14 @@ mod imp {
                 }
             }
         }
+
+        pub fn try_status(&self) -> Result<Option<ExitStatus>> {
+            self.child.lock().unwrap().try_wait()
+        }
+
+        pub fn status(&self) -> Result<ExitStatus> {
+            self.child.lock().unwrap().wait()
+        }
     }
 
     impl Drop for Process {
@@ -336,13 +446,13 @@ mod imp {
     }
 }
 
-/// Collect `PathOp` details into op-categories to pass onto the exec'd command as env-vars
-///
-/// WRITTEN -> `notify::ops::WRITE`, `notify::ops::CLOSE_WRITE`
-/// META_CHANGED -> `notify::ops::CHMOD`
-/// REMOVED -> `notify::ops::REMOVE`
-/// CREATED -> `notify::ops::CREATE`
-/// RENAMED -> `notify::ops::RENAME`
+// Collect `PathOp` details into op-categories to pass onto the exec'd command as env-vars
+//
+// WRITTEN -> `notify::ops::WRITE`, `notify::ops::CLOSE_WRITE`
+// META_CHANGED -> `notify::ops::CHMOD`
+// REMOVED -> `notify::ops::REMOVE`
+// CREATED -> `notify::ops::CREATE`
+// RENAMED -> `notify::ops::RENAME`
 // fn collect_path_env_vars(pathops: &[PathOp]) -> Vec<(String, String)> {
 //     #[cfg(target_family = "unix")]
 //     const ENV_SEP: &'static str = ":";
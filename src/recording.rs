@@ -0,0 +1,330 @@
+// Record and replay of commands, enabled by the `serde` feature.
+//
+// A recording is a JSON array with one entry per run of a command or a
+// pipeline, in the order they ran. Runs are matched on the program, args, env
+// overrides and working directory of each stage, and on their input; a
+// working directory inside the current one is stored relative to it, so that
+// recordings can be checked in.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::{executor, CommandContext, CommandError, Executor, Input, Pipeline, Timeout};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Recorded {
+    /// One for a plain command, and one per stage for a pipeline.
+    stages: Vec<Stage>,
+    /// What was fed to the first stage, if anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdin: Option<Data>,
+    /// What the last stage wrote.
+    #[serde(default)]
+    stdout: Data,
+    /// Set if the run was stopped by its timeout, in which case nothing else
+    /// about its outcome was recorded.
+    #[serde(default, skip_serializing_if = "is_false")]
+    timed_out: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+    /// The variables set on the command; `null` means removed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, Option<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<PathBuf>,
+    /// Missing if the command was killed by a signal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    #[serde(default)]
+    stderr: Data,
+}
+
+/// Output is kept as text, unless it isn't valid UTF-8.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Default for Data {
+    fn default() -> Data {
+        Data::Text(String::new())
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data {
+        match String::from_utf8(bytes) {
+            Ok(text) => Data::Text(text),
+            Err(err) => Data::Bytes(err.into_bytes()),
+        }
+    }
+}
+
+impl Data {
+    fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            Data::Text(ref text) => text.clone().into_bytes(),
+            Data::Bytes(ref bytes) => bytes.clone(),
+        }
+    }
+}
+
+impl Recorded {
+    // Describes a run of `stages` with `stdin`, with an empty outcome.
+    fn new(stages: &[Command], stdin: Option<&[u8]>) -> io::Result<Recorded> {
+        Ok(Recorded {
+            stages: stages.iter().map(Stage::new).collect::<io::Result<_>>()?,
+            stdin: stdin.map(|stdin| Data::from(stdin.to_vec())),
+            stdout: Data::default(),
+            timed_out: false,
+        })
+    }
+
+    fn same_run(&self, other: &Recorded) -> bool {
+        let stdin = |recorded: &Recorded| recorded.stdin.as_ref().map(Data::to_bytes);
+        self.stages.len() == other.stages.len()
+            && self.stages.iter().zip(&other.stages).all(|(a, b)| a.same_command(b))
+            && stdin(self) == stdin(other)
+    }
+
+    fn outputs(&self) -> io::Result<Vec<Output>> {
+        if self.timed_out {
+            return Err(timed_out("the command ran out of time when it was recorded"));
+        }
+        let last = self.stages.len().saturating_sub(1);
+        Ok(self
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(index, stage)| Output {
+                status: stage.status(),
+                stdout: if index == last { self.stdout.to_bytes() } else { vec![] },
+                stderr: stage.stderr.to_bytes(),
+            })
+            .collect())
+    }
+}
+
+impl Stage {
+    // Describes `command`, with an empty outcome.
+    fn new(command: &Command) -> io::Result<Stage> {
+        let context = CommandContext::new(command);
+        let text = |value: &::std::ffi::OsStr| {
+            value.to_str().map(str::to_string).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not valid UTF-8", value))
+            })
+        };
+        Ok(Stage {
+            program: text(&context.program)?,
+            args: context.args.iter().map(|arg| text(arg)).collect::<io::Result<_>>()?,
+            env: context
+                .env
+                .iter()
+                .map(|(key, value)| Ok((text(key)?, value.as_deref().map(text).transpose()?)))
+                .collect::<io::Result<_>>()?,
+            cwd: context.cwd.map(|cwd| relative_cwd(&cwd)),
+            code: None,
+            signal: None,
+            stderr: Data::default(),
+        })
+    }
+
+    fn same_command(&self, other: &Stage) -> bool {
+        self.program == other.program && self.args == other.args && self.env == other.env && self.cwd == other.cwd
+    }
+
+    // The command line with its env and working directory, like `shell_string`.
+    fn command_line(&self) -> String {
+        let env = self.env.iter().map(|(key, value)| (key.as_ref(), value.as_deref().map(AsRef::as_ref)));
+        let words = iter::once(&self.program).chain(&self.args);
+        let command_line = words.map(|word| super::shell_quote(word)).collect::<Vec<_>>().join(" ");
+        super::context::shell_string(self.cwd.as_deref(), env, &command_line)
+    }
+
+    #[cfg(unix)]
+    fn status(&self) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+
+        match self.signal {
+            Some(signal) => ExitStatus::from_raw(signal),
+            None => executor::exit_status(self.code.unwrap_or(0)),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn status(&self) -> ExitStatus {
+        executor::exit_status(self.code.unwrap_or(0))
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+// How an executor reports a run that ran out of time.
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
+// Makes `cwd` relative to the current directory if it lies inside it.
+fn relative_cwd(cwd: &Path) -> PathBuf {
+    let current = env::current_dir().ok();
+    let canonical = current.as_ref().and_then(|dir| fs::canonicalize(dir).ok());
+    for base in iter::once(current).chain(iter::once(canonical)).flatten() {
+        if let Ok(relative) = cwd.strip_prefix(&base) {
+            return match relative.as_os_str().is_empty() {
+                true => PathBuf::from("."),
+                false => relative.to_owned(),
+            };
+        }
+    }
+    cwd.to_owned()
+}
+
+/// An `Executor` that runs commands and records them, with their output and
+/// exit status, into a file that `Replayer` can serve them back from.
+///
+/// Commands run like they would without an executor: pipelines are
+/// connected, timeouts apply, and the data given to `execute_with_input` is
+/// fed to them and recorded as well. The output is captured, so commands run
+/// with `execute()` don't print anything. The file is rewritten after every
+/// run. Runs that can't be started aren't recorded.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    recorded: Mutex<Vec<Recorded>>,
+}
+
+impl Recorder {
+    /// Starts an empty recording at `path`, replacing what was there.
+    pub fn create<P: Into<PathBuf>>(path: P) -> Result<Recorder, CommandError> {
+        let recorder = Recorder {
+            path: path.into(),
+            recorded: Mutex::new(vec![]),
+        };
+        recorder.save(&[]).map_err(|err| CommandError::Io(err, None))?;
+        Ok(recorder)
+    }
+
+    fn save(&self, recorded: &[Recorded]) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(recorded).map_err(io::Error::other)?;
+        fs::write(&self.path, json + "\n")
+    }
+}
+
+impl Executor for Recorder {
+    fn run(&self, stages: Vec<Command>, input: Option<Input>, timeout: Option<Timeout>) -> io::Result<Vec<Output>> {
+        let stdin = input.map(Input::into_bytes).transpose()?;
+        let mut entry = Recorded::new(&stages, stdin.as_deref())?;
+        let mut pipeline = Pipeline::new(stages);
+        if let Some(timeout) = timeout {
+            pipeline = pipeline.timeout(timeout);
+        }
+        let result = match pipeline.output(stdin.map(Input::Bytes)) {
+            Ok(outputs) => {
+                for (stage, output) in entry.stages.iter_mut().zip(&outputs) {
+                    stage.code = output.status.code();
+                    stage.signal = signal(output.status);
+                    stage.stderr = Data::from(output.stderr.clone());
+                }
+                if let Some(last) = outputs.last() {
+                    entry.stdout = Data::from(last.stdout.clone());
+                }
+                Ok(outputs)
+            }
+            Err(err @ CommandError::Timeout(..)) => {
+                entry.timed_out = true;
+                Err(timed_out(&err.to_string()))
+            }
+            Err(CommandError::Io(err, _)) => return Err(err),
+            Err(err) => return Err(io::Error::other(err.to_string())),
+        };
+
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.push(entry);
+        self.save(&recorded)?;
+        result
+    }
+}
+
+/// An `Executor` that spawns nothing and answers from a file written by
+/// `Recorder`.
+///
+/// Each run gets the first recorded run of the same commands, fed the same
+/// input, that wasn't replayed yet. A run that isn't in the recording fails
+/// with an IO error, and one that ran out of time while it was recorded
+/// times out again.
+#[derive(Debug)]
+pub struct Replayer {
+    recorded: Mutex<Vec<(Recorded, bool)>>,
+}
+
+impl Replayer {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replayer, CommandError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|err| CommandError::Io(err, None))?;
+        let recorded = serde_json::from_str::<Vec<Recorded>>(&json)
+            .map_err(|err| CommandError::Serialization(format!("{}: {}", path.display(), err)))?;
+        Ok(Replayer {
+            recorded: Mutex::new(recorded.into_iter().map(|entry| (entry, false)).collect()),
+        })
+    }
+
+    /// The recorded runs that weren't replayed, such as `git status` or
+    /// `cat log | grep error`.
+    pub fn unplayed(&self) -> Vec<String> {
+        self.recorded
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&(_, played)| !played)
+            .map(|(entry, _)| entry.stages.iter().map(Stage::command_line).collect::<Vec<_>>().join(" | "))
+            .collect()
+    }
+}
+
+impl Executor for Replayer {
+    fn run(&self, stages: Vec<Command>, input: Option<Input>, _timeout: Option<Timeout>) -> io::Result<Vec<Output>> {
+        let stdin = input.map(Input::into_bytes).transpose()?;
+        let wanted = Recorded::new(&stages, stdin.as_deref())?;
+        let mut recorded = self.recorded.lock().unwrap();
+        match recorded.iter_mut().find(|(entry, played)| !*played && entry.same_run(&wanted)) {
+            Some((entry, played)) => {
+                *played = true;
+                entry.outputs()
+            }
+            None => Err(io::Error::other("the command is not in the recording")),
+        }
+    }
+}
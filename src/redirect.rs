@@ -0,0 +1,349 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+#[cfg(not(unix))]
+use std::process::Stdio;
+
+use super::CommandError;
+#[cfg(not(unix))]
+use crate::input::{self, Input};
+
+/// One of the output streams of a command: what a redirection such as `2>`
+/// applies to, and what a line passed to `execute_lines` was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A redirection operator as it appears in the spec.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Redirection {
+    /// `<`
+    Read,
+    /// `>` or `2>`; `true` for the appending `>>` forms.
+    Write(Stream, bool),
+    /// `2>&1` or `>&2`
+    Duplicate(Stream, Stream),
+}
+
+/// A redirection together with its target, in the order it was written.
+#[derive(Debug, Clone)]
+pub enum Redirect {
+    Read(PathBuf),
+    Write(Stream, PathBuf, bool),
+    Duplicate(Stream, Stream),
+    /// The body of a `<<EOF` block.
+    Heredoc(OsString),
+}
+
+impl Redirect {
+    /// Renders the redirect the way a shell would read it. For a heredoc that
+    /// is only the `<<'EOF'` part, with a delimiter that doesn't occur in its
+    /// body; the body goes after the command line, see `heredoc_lines`.
+    pub fn to_shell_string(&self) -> String {
+        let quote = |path: &Path| super::shell_quote(&path.to_string_lossy());
+        let fd = |stream| match stream {
+            Stream::Stdout => "",
+            Stream::Stderr => "2",
+        };
+        match *self {
+            Redirect::Read(ref path) => format!("< {}", quote(path)),
+            Redirect::Write(stream, ref path, append) => {
+                format!("{}{} {}", fd(stream), if append { ">>" } else { ">" }, quote(path))
+            }
+            Redirect::Duplicate(from, to) => format!("{}>&{}", fd(from), if to == Stream::Stdout { 1 } else { 2 }),
+            Redirect::Heredoc(ref body) => format!("<<'{}'", delimiter(&body.to_string_lossy())),
+        }
+    }
+
+    /// The same redirect with a relative path joined onto `cd`.
+    fn resolve(&self, cd: &Path) -> Redirect {
+        match *self {
+            Redirect::Read(ref path) => Redirect::Read(cd.join(path)),
+            Redirect::Write(stream, ref path, append) => Redirect::Write(stream, cd.join(path), append),
+            ref other => other.clone(),
+        }
+    }
+
+    /// The lines that follow the command line for a heredoc: its body and
+    /// then its delimiter.
+    pub fn heredoc_lines(&self) -> Option<String> {
+        match *self {
+            Redirect::Heredoc(ref body) => {
+                let body = body.to_string_lossy();
+                // The delimiter has to start a line of its own.
+                let newline = if body.is_empty() || body.ends_with('\n') { "" } else { "\n" };
+                Some(format!("{}{}{}", body, newline, delimiter(&body)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn delimiter(body: &str) -> String {
+    let mut delimiter = "EOF".to_string();
+    while body.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+/// What the redirects of a stage took over, so a `Pipeline` knows which
+/// streams it may still wire up.
+#[derive(Debug, Default, Clone)]
+pub struct StageIo {
+    pub stdin_redirected: bool,
+    pub stdout_redirected: bool,
+    /// Set for `2>&1` while stdout still pointed at its default.
+    pub stderr_to_stdout: bool,
+    /// The redirects with their paths resolved, to find the culprit when
+    /// the stage fails to spawn, and to show them in a dry run.
+    pub redirects: Vec<Redirect>,
+}
+
+impl StageIo {
+    /// Finds the first redirect whose file can't be opened, without creating
+    /// or truncating anything.
+    pub fn blame(&self) -> Option<CommandError> {
+        self.redirects.iter().find_map(|redirect| {
+            let (path, result) = match *redirect {
+                Redirect::Read(ref path) => (path, File::open(path).map(drop)),
+                Redirect::Write(_, ref path, _) if path.exists() => {
+                    (path, OpenOptions::new().write(true).open(path).map(drop))
+                }
+                Redirect::Write(_, ref path, _) => (path, path.parent().map_or(Ok(()), |dir| dir.metadata().map(drop))),
+                _ => return None,
+            };
+            result.err().map(|err| CommandError::Redirect(path.clone(), err))
+        })
+    }
+}
+
+/// Attaches `redirects` to `command`, with relative paths resolved against `cd`.
+///
+/// Nothing is opened yet. The child opens the files right before it runs
+/// the program, in the order they were written, so a command that is never
+/// spawned leaves them alone, and `2>&1` joins whatever stdout the child
+/// ended up with. A heredoc goes through an unlinked temporary file.
+#[cfg(unix)]
+pub fn apply(command: &mut Command, redirects: &[Redirect], cd: &Path) -> Result<StageIo, CommandError> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static HEREDOCS: AtomicUsize = AtomicUsize::new(0);
+
+    enum Action {
+        Open(CString, libc::c_int, libc::c_int),
+        Duplicate(libc::c_int, libc::c_int),
+        // The path ends in a NUL after room for the pid of the child.
+        Heredoc(Vec<u8>, Vec<u8>),
+    }
+
+    let fd = |stream| match stream {
+        Stream::Stdout => libc::STDOUT_FILENO,
+        Stream::Stderr => libc::STDERR_FILENO,
+    };
+    let c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes()).map_err(|err| CommandError::Redirect(path.to_owned(), err.into()))
+    };
+
+    let resolved = redirects.iter().map(|redirect| redirect.resolve(cd)).collect::<Vec<_>>();
+    let mut actions = vec![];
+    for redirect in &resolved {
+        actions.push(match *redirect {
+            Redirect::Read(ref path) => Action::Open(c_path(path)?, libc::O_RDONLY, libc::STDIN_FILENO),
+            Redirect::Write(stream, ref path, append) => {
+                let mode = if append { libc::O_APPEND } else { libc::O_TRUNC };
+                Action::Open(c_path(path)?, libc::O_WRONLY | libc::O_CREAT | mode, fd(stream))
+            }
+            Redirect::Duplicate(from, to) => Action::Duplicate(fd(to), fd(from)),
+            Redirect::Heredoc(ref body) => {
+                let name = format!(
+                    "commandspec-heredoc-{}-{}",
+                    std::process::id(),
+                    HEREDOCS.fetch_add(1, Ordering::Relaxed)
+                );
+                let path = std::env::temp_dir().join(name);
+                let mut path = c_path(&path)?.into_bytes();
+                path.extend_from_slice(b"-0000000000\0");
+                Action::Heredoc(path, body.clone().into_encoded_bytes())
+            }
+        });
+    }
+    if actions.is_empty() {
+        return Ok(StageIo::default());
+    }
+
+    // Only async-signal-safe calls from here on; this runs between fork and exec.
+    let check = |result: libc::c_int| if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result) };
+    let run = move || -> io::Result<()> {
+        for action in &mut actions {
+            match *action {
+                Action::Open(ref path, flags, target) => {
+                    let file = check(unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC, 0o666) })?;
+                    check(unsafe { libc::dup2(file, target) })?;
+                    unsafe { libc::close(file) };
+                }
+                Action::Duplicate(from, to) => {
+                    check(unsafe { libc::dup2(from, to) })?;
+                }
+                Action::Heredoc(ref mut path, ref body) => {
+                    // A reused `Command` can be spawned twice at once, so the
+                    // file is named after the child.
+                    let mut pid = unsafe { libc::getpid() } as u32;
+                    let end = path.len() - 1;
+                    for digit in path[end - 10..end].iter_mut().rev() {
+                        *digit = b'0' + (pid % 10) as u8;
+                        pid /= 10;
+                    }
+                    let path = path.as_ptr() as *const libc::c_char;
+                    let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+                    let file = check(unsafe { libc::open(path, flags | libc::O_CLOEXEC, 0o600) })?;
+                    unsafe { libc::unlink(path) };
+                    let mut written = 0;
+                    while written < body.len() {
+                        let rest = &body[written..];
+                        let count = unsafe { libc::write(file, rest.as_ptr() as *const _, rest.len()) };
+                        if count >= 0 {
+                            written += count as usize;
+                            continue;
+                        }
+                        let err = io::Error::last_os_error();
+                        if err.kind() != io::ErrorKind::Interrupted {
+                            return Err(err);
+                        }
+                    }
+                    if unsafe { libc::lseek(file, 0, libc::SEEK_SET) } < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    check(unsafe { libc::dup2(file, libc::STDIN_FILENO) })?;
+                    unsafe { libc::close(file) };
+                }
+            }
+        }
+        Ok(())
+    };
+    unsafe {
+        command.pre_exec(run);
+    }
+    // The child rearranges its own descriptors, so a pipeline can wire up
+    // every stream as usual and let the redirects take over from there.
+    Ok(StageIo {
+        redirects: resolved,
+        ..StageIo::default()
+    })
+}
+
+#[cfg(not(unix))]
+enum Sink {
+    Parent(Stream),
+    File(File, PathBuf),
+}
+
+#[cfg(not(unix))]
+impl Sink {
+    fn try_clone(&self) -> Result<Sink, CommandError> {
+        match *self {
+            Sink::Parent(stream) => Ok(Sink::Parent(stream)),
+            Sink::File(ref file, ref path) => file
+                .try_clone()
+                .map(|file| Sink::File(file, path.clone()))
+                .map_err(|err| CommandError::Redirect(path.clone(), err)),
+        }
+    }
+}
+
+/// Opens the files named by `redirects`, relative to `cd`, and attaches them to `command`.
+///
+/// Without `pre_exec` the files have to be opened here, when the command is built.
+#[cfg(not(unix))]
+pub fn apply(command: &mut Command, redirects: &[Redirect], cd: &Path) -> Result<StageIo, CommandError> {
+    let mut io = StageIo::default();
+    let mut stdout = Sink::Parent(Stream::Stdout);
+    let mut stderr = Sink::Parent(Stream::Stderr);
+
+    for redirect in redirects {
+        match *redirect {
+            Redirect::Read(ref path) => {
+                let (file, _) = open(cd, path, OpenOptions::new().read(true))?;
+                command.stdin(file);
+                io.stdin_redirected = true;
+            }
+            Redirect::Heredoc(ref body) => {
+                // The body is written as soon as the command reads it. If the
+                // command is never run, the write fails once it is dropped.
+                let (reader, writer) = io::pipe().map_err(|err| CommandError::Io(err, None))?;
+                command.stdin(reader);
+                input::feed(Input::from(body.clone().into_encoded_bytes()), writer);
+                io.stdin_redirected = true;
+            }
+            Redirect::Write(stream, ref path, append) => {
+                let (file, path) = open(
+                    cd,
+                    path,
+                    OpenOptions::new().write(true).create(true).append(append).truncate(!append),
+                )?;
+                match stream {
+                    Stream::Stdout => stdout = Sink::File(file, path),
+                    Stream::Stderr => stderr = Sink::File(file, path),
+                }
+            }
+            Redirect::Duplicate(Stream::Stderr, Stream::Stdout) => stderr = stdout.try_clone()?,
+            Redirect::Duplicate(Stream::Stdout, Stream::Stderr) => stdout = stderr.try_clone()?,
+            Redirect::Duplicate(_, _) => {}
+        }
+    }
+
+    match stdout {
+        Sink::Parent(Stream::Stdout) => {}
+        Sink::Parent(Stream::Stderr) => {
+            command.stdout(parent_stream(Stream::Stderr)?);
+            io.stdout_redirected = true;
+        }
+        Sink::File(file, _) => {
+            command.stdout(file);
+            io.stdout_redirected = true;
+        }
+    }
+    match stderr {
+        Sink::Parent(Stream::Stderr) => {}
+        Sink::Parent(Stream::Stdout) => {
+            command.stderr(parent_stream(Stream::Stdout)?);
+            io.stderr_to_stdout = true;
+        }
+        Sink::File(file, _) => {
+            command.stderr(file);
+        }
+    }
+
+    io.redirects = redirects.iter().map(|redirect| redirect.resolve(cd)).collect();
+    Ok(io)
+}
+
+#[cfg(not(unix))]
+fn open(cd: &Path, path: &Path, options: &OpenOptions) -> Result<(File, PathBuf), CommandError> {
+    let path = cd.join(path);
+    match options.open(&path) {
+        Ok(file) => Ok((file, path)),
+        Err(err) => Err(CommandError::Redirect(path, err)),
+    }
+}
+
+// Duplicates one of our own standard streams so the child can write to it
+// in place of the other one.
+#[cfg(windows)]
+fn parent_stream(stream: Stream) -> Result<Stdio, CommandError> {
+    use std::os::windows::io::AsHandle;
+
+    let handle = match stream {
+        Stream::Stdout => io::stdout().as_handle().try_clone_to_owned(),
+        Stream::Stderr => io::stderr().as_handle().try_clone_to_owned(),
+    };
+    handle.map(Stdio::from).map_err(|err| CommandError::Io(err, None))
+}
@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+use super::{build_pipeline, dry_run, CommandError, CommandSpec, Pipeline};
+use crate::timeout::Timeout;
+
+/// How a step of a `Sequence` depends on the outcome of the steps before it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Connector {
+    /// `;` runs the step regardless.
+    Then,
+    /// `&&` runs the step only if the previous one succeeded.
+    And,
+    /// `||` runs the step only if the previous one failed.
+    Or,
+}
+
+/// Pipelines joined by `&&`, `||` and `;`, run in order with the same
+/// short-circuiting as a shell.
+///
+/// Each step is only turned into commands right before it runs, so a step can
+/// redirect into a directory that an earlier step created. A dry run shows
+/// the whole sequence at once without building any step.
+#[derive(Debug)]
+pub struct Sequence {
+    steps: Vec<(Connector, Vec<CommandSpec>)>,
+    timeout: Option<Timeout>,
+}
+
+// The connector of the first step is ignored. All steps share the preamble,
+// so the timeout is taken from the first one.
+pub fn from_specs(steps: Vec<(Connector, Vec<CommandSpec>)>) -> Sequence {
+    let timeout = steps.first().and_then(|(_, specs)| specs.first()).and_then(|spec| spec.timeout);
+    Sequence {
+        steps,
+        timeout: timeout.map(Timeout::new),
+    }
+}
+
+impl Sequence {
+    /// The number of pipelines in this sequence.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Limits how long the whole sequence may run. Replaces a `timeout` from the spec.
+    pub fn timeout(mut self, timeout: Timeout) -> Sequence {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Runs the sequence. Like a shell, the outcome is that of the last step
+    /// that ran, so `a || b` succeeds when `b` does.
+    pub fn execute(self) -> Result<(), CommandError> {
+        if dry_run::intercept_sequence(&self.steps) {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut result = Ok(());
+        for index in 0..self.steps.len() {
+            if self.runs(index, &result) {
+                let offset = start.elapsed();
+                result = self.pipeline(index, offset).and_then(Pipeline::execute).map_err(step_error(index, offset));
+            }
+        }
+        self.outcome(result)
+    }
+
+    /// Like `execute`, but waits for each step on a blocking task of the tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn execute_async(self) -> Result<(), CommandError> {
+        if dry_run::intercept_sequence(&self.steps) {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut result = Ok(());
+        for index in 0..self.steps.len() {
+            if self.runs(index, &result) {
+                let offset = start.elapsed();
+                result = match self.pipeline(index, offset) {
+                    Ok(pipeline) => pipeline.execute_async().await,
+                    Err(err) => Err(err),
+                }
+                .map_err(step_error(index, offset));
+            }
+        }
+        self.outcome(result)
+    }
+
+    // Whether the step at `index` runs, given the result of the last step that ran.
+    fn runs(&self, index: usize, result: &Result<(), (usize, CommandError)>) -> bool {
+        match self.steps[index].0 {
+            _ if index == 0 => true,
+            Connector::Then => true,
+            Connector::And => result.is_ok(),
+            Connector::Or => result.is_err(),
+        }
+    }
+
+    // Builds the step at `index`, which starts `offset` into the sequence.
+    fn pipeline(&self, index: usize, offset: Duration) -> Result<Pipeline, CommandError> {
+        let pipeline = build_pipeline(&self.steps[index].1)?;
+        Ok(match self.timeout {
+            // Each step gets what is left of the time.
+            Some(timeout) => {
+                let left = timeout.duration().checked_sub(offset).unwrap_or_default();
+                pipeline.timeout(timeout.with_duration(left))
+            }
+            None => pipeline,
+        })
+    }
+
+    fn outcome(&self, result: Result<(), (usize, CommandError)>) -> Result<(), CommandError> {
+        result.map_err(|(index, err)| {
+            if self.steps.len() == 1 {
+                err
+            } else {
+                CommandError::Step(index, Box::new(err))
+            }
+        })
+    }
+}
+
+// Remembers which step failed, and counts a timeout from the start of the sequence.
+fn step_error(index: usize, offset: Duration) -> impl Fn(CommandError) -> (usize, CommandError) {
+    move |err| match err {
+        CommandError::Timeout(elapsed, context) => (index, CommandError::Timeout(offset + elapsed, context)),
+        err => (index, err),
+    }
+}
@@ -0,0 +1,237 @@
+// Serde support for `CommandSpec`, enabled by the `serde` feature, with YAML
+// behind the `yaml` feature.
+//
+// Specs go through `SpecData`, which uses plain strings so that config
+// files stay readable. Values that aren't valid UTF-8 can't be serialized.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{self, SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+
+use super::{CommandError, CommandSpec};
+use crate::redirect::{Redirect, Stream};
+use crate::timeout;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecData {
+    program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<PathBuf>,
+    /// A duration such as `30s`, like the `timeout` line of a spec.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<String>,
+    /// Redirections in the order they apply.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stdio: Vec<StdioData>,
+}
+
+/// A redirection, written as a single-key map such as `{ stdout = "log" }`,
+/// or as a plain string if it has no target. Serde's own enum format would
+/// turn into a tag in YAML, so this is done by hand.
+enum StdioData {
+    /// `< file`
+    Stdin(PathBuf),
+    /// `<<EOF`
+    StdinText(String),
+    /// `> file`
+    Stdout(PathBuf),
+    /// `>> file`
+    StdoutAppend(PathBuf),
+    /// `2> file`
+    Stderr(PathBuf),
+    /// `2>> file`
+    StderrAppend(PathBuf),
+    /// `2>&1`
+    StderrToStdout,
+    /// `>&2`
+    StdoutToStderr,
+}
+
+const STDIO_TARGETS: &[&str] = &["stdin", "stdin_text", "stdout", "stdout_append", "stderr", "stderr_append"];
+const STDIO_NAMES: &[&str] = &["stderr_to_stdout", "stdout_to_stderr"];
+
+impl Serialize for StdioData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (key, path) = match *self {
+            StdioData::StderrToStdout => return serializer.serialize_str("stderr_to_stdout"),
+            StdioData::StdoutToStderr => return serializer.serialize_str("stdout_to_stderr"),
+            StdioData::StdinText(ref text) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("stdin_text", text)?;
+                return map.end();
+            }
+            StdioData::Stdin(ref path) => ("stdin", path),
+            StdioData::Stdout(ref path) => ("stdout", path),
+            StdioData::StdoutAppend(ref path) => ("stdout_append", path),
+            StdioData::Stderr(ref path) => ("stderr", path),
+            StdioData::StderrAppend(ref path) => ("stderr_append", path),
+        };
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(key, path)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for StdioData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StdioData, D::Error> {
+        deserializer.deserialize_any(StdioVisitor)
+    }
+}
+
+struct StdioVisitor;
+
+impl<'de> Visitor<'de> for StdioVisitor {
+    type Value = StdioData;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a redirection such as {{ stdout = \"log\" }} or \"stderr_to_stdout\"")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<StdioData, E> {
+        match value {
+            "stderr_to_stdout" => Ok(StdioData::StderrToStdout),
+            "stdout_to_stderr" => Ok(StdioData::StdoutToStderr),
+            _ => Err(E::unknown_variant(value, STDIO_NAMES)),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<StdioData, A::Error> {
+        let key = map.next_key::<String>()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let stdio = match key.as_str() {
+            "stdin" => StdioData::Stdin(map.next_value()?),
+            "stdin_text" => StdioData::StdinText(map.next_value()?),
+            "stdout" => StdioData::Stdout(map.next_value()?),
+            "stdout_append" => StdioData::StdoutAppend(map.next_value()?),
+            "stderr" => StdioData::Stderr(map.next_value()?),
+            "stderr_append" => StdioData::StderrAppend(map.next_value()?),
+            _ => return Err(de::Error::unknown_variant(&key, STDIO_TARGETS)),
+        };
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(2, &self));
+        }
+        Ok(stdio)
+    }
+}
+
+impl Serialize for CommandSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = |value: &OsString| {
+            value
+                .to_str()
+                .map(str::to_string)
+                .ok_or_else(|| ser::Error::custom(format!("{:?} is not valid UTF-8", value)))
+        };
+        let mut stdio = vec![];
+        for redirect in &self.redirects {
+            stdio.push(match *redirect {
+                Redirect::Read(ref path) => StdioData::Stdin(path.clone()),
+                Redirect::Heredoc(ref body) => StdioData::StdinText(text(body)?),
+                Redirect::Write(Stream::Stdout, ref path, false) => StdioData::Stdout(path.clone()),
+                Redirect::Write(Stream::Stdout, ref path, true) => StdioData::StdoutAppend(path.clone()),
+                Redirect::Write(Stream::Stderr, ref path, false) => StdioData::Stderr(path.clone()),
+                Redirect::Write(Stream::Stderr, ref path, true) => StdioData::StderrAppend(path.clone()),
+                Redirect::Duplicate(Stream::Stderr, Stream::Stdout) => StdioData::StderrToStdout,
+                Redirect::Duplicate(Stream::Stdout, Stream::Stderr) => StdioData::StdoutToStderr,
+                // `>&1` and `2>&2` don't change anything.
+                Redirect::Duplicate(_, _) => continue,
+            });
+        }
+        SpecData {
+            program: text(&self.binary)?,
+            args: self.args.iter().map(text).collect::<Result<_, _>>()?,
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| Ok((text(key)?, text(value)?)))
+                .collect::<Result<_, S::Error>>()?,
+            cwd: self.cd.clone(),
+            timeout: self.timeout.map(|timeout| format!("{}s", timeout.as_secs_f64())),
+            stdio,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CommandSpec, D::Error> {
+        let data = SpecData::deserialize(deserializer)?;
+        let timeout = match data.timeout {
+            Some(ref value) => Some(
+                timeout::parse_duration(value)
+                    .ok_or_else(|| de::Error::custom(format!("invalid timeout {:?}, expected e.g. 30s", value)))?,
+            ),
+            None => None,
+        };
+        let redirects = data
+            .stdio
+            .into_iter()
+            .map(|stdio| match stdio {
+                StdioData::Stdin(path) => Redirect::Read(path),
+                StdioData::StdinText(body) => Redirect::Heredoc(body.into()),
+                StdioData::Stdout(path) => Redirect::Write(Stream::Stdout, path, false),
+                StdioData::StdoutAppend(path) => Redirect::Write(Stream::Stdout, path, true),
+                StdioData::Stderr(path) => Redirect::Write(Stream::Stderr, path, false),
+                StdioData::StderrAppend(path) => Redirect::Write(Stream::Stderr, path, true),
+                StdioData::StderrToStdout => Redirect::Duplicate(Stream::Stderr, Stream::Stdout),
+                StdioData::StdoutToStderr => Redirect::Duplicate(Stream::Stdout, Stream::Stderr),
+            })
+            .collect();
+        Ok(CommandSpec {
+            binary: data.program.into(),
+            args: data.args.into_iter().map(OsString::from).collect(),
+            env: data
+                .env
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect::<HashMap<_, _>>(),
+            cd: data.cwd,
+            timeout,
+            redirects,
+        })
+    }
+}
+
+impl CommandSpec {
+    /// Reads a spec from JSON, such as `{"program": "cargo", "args": ["build"]}`.
+    pub fn from_json(json: &str) -> Result<CommandSpec, CommandError> {
+        serde_json::from_str(json).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+
+    /// Writes the spec as pretty-printed JSON. Fails on values that aren't valid UTF-8.
+    pub fn to_json(&self) -> Result<String, CommandError> {
+        serde_json::to_string_pretty(self).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+
+    /// Reads a spec from TOML, with the same fields as `from_json`.
+    pub fn from_toml(toml: &str) -> Result<CommandSpec, CommandError> {
+        toml::from_str(toml).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+
+    /// Writes the spec as TOML, like `to_json`.
+    pub fn to_toml(&self) -> Result<String, CommandError> {
+        toml::to_string(self).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+
+    /// Reads a spec from YAML, with the same fields as `from_json`. Needs the
+    /// `yaml` feature.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<CommandSpec, CommandError> {
+        serde_norway::from_str(yaml).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+
+    /// Writes the spec as YAML, like `to_json`. Needs the `yaml` feature.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Result<String, CommandError> {
+        serde_norway::to_string(self).map_err(|err| CommandError::Serialization(err.to_string()))
+    }
+}
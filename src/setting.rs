@@ -0,0 +1,50 @@
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::thread::LocalKey;
+
+/// A process-wide value that a thread can override for the duration of a
+/// closure, like the shell of `set_shell` and `with_shell`.
+///
+/// Statics can't be generic, so every setting declares its own
+/// `thread_local!` slot, starting out as `None`, and passes it to `new`.
+pub struct Override<T: 'static> {
+    global: Mutex<T>,
+    local: &'static LocalKey<RefCell<Option<T>>>,
+}
+
+impl<T: Clone> Override<T> {
+    pub fn new(value: T, local: &'static LocalKey<RefCell<Option<T>>>) -> Override<T> {
+        Override {
+            global: Mutex::new(value),
+            local,
+        }
+    }
+
+    /// Sets the value for the whole process.
+    pub fn set(&self, value: T) {
+        *self.global.lock().unwrap() = value;
+    }
+
+    /// Runs `f` with `value` in place of the process-wide value, on the
+    /// current thread only.
+    pub fn with<R, F: FnOnce() -> R>(&self, value: T, f: F) -> R {
+        // Restores the previous value even if `f` panics.
+        struct Restore<T: 'static>(&'static LocalKey<RefCell<Option<T>>>, Option<T>);
+        impl<T: 'static> Drop for Restore<T> {
+            fn drop(&mut self) {
+                let previous = self.1.take();
+                self.0.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let _restore = Restore(self.local, self.local.with(|current| current.borrow_mut().replace(value)));
+        f()
+    }
+
+    /// The value for the current thread.
+    pub fn get(&self) -> T {
+        self.local
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(|| self.global.lock().unwrap().clone())
+    }
+}
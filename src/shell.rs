@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::process::Command;
+
+use super::{CommandError, CommandSpec};
+use crate::redirect::Redirect;
+use crate::setting::Override;
+
+/// The shell that `sh_command!` and `sh_execute!` run scripts with.
+///
+/// A script runs as `program flags.. -c script`, with the prelude lines put
+/// in front of it. The default is `Shell::sh()`, which is `sh` with `set -e`.
+/// Change it for the whole process with `set_shell`, or for the current
+/// thread with `with_shell`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shell {
+    program: OsString,
+    flags: Vec<OsString>,
+    prelude: Vec<String>,
+    stdin: bool,
+}
+
+thread_local! {
+    static LOCAL_SHELL: RefCell<Option<Shell>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref SHELL: Override<Shell> = Override::new(Shell::sh(), &LOCAL_SHELL);
+}
+
+/// Sets the shell for the whole process.
+pub fn set_shell(shell: Shell) {
+    SHELL.set(shell);
+}
+
+/// Runs `f` with `shell` in place of the process-wide one, on the current
+/// thread only.
+pub fn with_shell<R, F: FnOnce() -> R>(shell: Shell, f: F) -> R {
+    SHELL.with(shell, f)
+}
+
+impl Shell {
+    /// Runs scripts with `program` as is, without flags or prelude.
+    pub fn new<S: Into<OsString>>(program: S) -> Shell {
+        Shell {
+            program: program.into(),
+            flags: vec![],
+            prelude: vec![],
+            stdin: false,
+        }
+    }
+
+    /// `sh` that stops at the first failing command.
+    pub fn sh() -> Shell {
+        Shell::new("sh").prelude("set -e")
+    }
+
+    /// `bash` that stops at the first failing command.
+    pub fn bash() -> Shell {
+        Shell::new("bash").prelude("set -e")
+    }
+
+    /// `bash` that also fails on unset variables and on any failing stage of
+    /// a pipeline, with `set -euo pipefail`.
+    pub fn bash_strict() -> Shell {
+        Shell::new("bash").prelude("set -euo pipefail")
+    }
+
+    /// `dash` that stops at the first failing command.
+    pub fn dash() -> Shell {
+        Shell::new("dash").prelude("set -e")
+    }
+
+    /// `zsh` that stops at the first failing command.
+    pub fn zsh() -> Shell {
+        Shell::new("zsh").prelude("set -e")
+    }
+
+    /// The shell used by `sh_command!` on this thread.
+    pub fn current() -> Shell {
+        SHELL.get()
+    }
+
+    /// Adds a flag that goes before `-c`, such as `--noprofile`.
+    pub fn flag<S: Into<OsString>>(mut self, flag: S) -> Shell {
+        self.flags.push(flag.into());
+        self
+    }
+
+    /// Adds a line that runs before every script, such as `shopt -s globstar`.
+    pub fn prelude<S: Into<String>>(mut self, line: S) -> Shell {
+        self.prelude.push(line.into());
+        self
+    }
+
+    /// Feeds the script to the shell's stdin with `-s` instead of passing it
+    /// with `-c`, which gets around the limit on the length of arguments. The
+    /// commands in the script then can't read our stdin.
+    pub fn script_via_stdin(mut self) -> Shell {
+        self.stdin = true;
+        self
+    }
+
+    /// Builds the `Command` that runs `script`.
+    pub fn command(&self, script: &str) -> Result<Command, CommandError> {
+        self.spec(None, script).to_command()
+    }
+
+    /// Like `command`, but runs the shell as another user through `pkexec`.
+    pub fn pkexec_command(&self, script: &str) -> Result<Command, CommandError> {
+        self.spec(Some("pkexec"), script).to_command()
+    }
+
+    fn spec(&self, wrapper: Option<&str>, script: &str) -> CommandSpec {
+        let mut text = self.prelude.join("\n");
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(script);
+
+        let mut words = wrapper.into_iter().map(OsString::from).collect::<Vec<_>>();
+        words.push(self.program.clone());
+        words.extend(self.flags.iter().cloned());
+        let mut redirects = vec![];
+        if self.stdin {
+            // Like a heredoc, the script ends with a newline.
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+            words.push("-s".into());
+            redirects.push(Redirect::Heredoc(text.into()));
+        } else {
+            words.push("-c".into());
+            words.push(text.into());
+        }
+        CommandSpec {
+            binary: words.remove(0),
+            args: words,
+            env: HashMap::new(),
+            cd: None,
+            timeout: None,
+            redirects,
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell::sh()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shell;
+
+    #[test]
+    fn shell_script() {
+        let spec = Shell::bash_strict().flag("--noprofile").spec(None, "echo hi");
+        assert_eq!(spec.binary, "bash");
+        assert_eq!(spec.args, vec!["--noprofile", "-c", "set -euo pipefail\n\necho hi"]);
+
+        let spec = Shell::new("zsh").script_via_stdin().spec(Some("pkexec"), "echo hi");
+        assert_eq!(spec.binary, "pkexec");
+        assert_eq!(spec.args, vec!["zsh", "-s"]);
+        assert_eq!(spec.to_shell_string().lines().last(), Some("EOF"));
+    }
+}
@@ -2,9 +2,9 @@
 #![allow(unused)]
 
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-type CleanupMutex = Mutex<Option<Box<Fn(self::Signal) + Send>>>;
+type CleanupMutex = Mutex<Option<Box<dyn Fn(self::Signal) + Send>>>;
 
 lazy_static! {
     static ref CLEANUP: CleanupMutex = Mutex::new(None);
@@ -42,19 +42,8 @@ pub trait ConvertToLibc {
 #[cfg(unix)]
 impl ConvertToLibc for Signal {
     fn convert_to_libc(self) -> c_int {
-        // Convert from signal::Signal enum to libc::* c_int constants
-        match self {
-            Signal::SIGKILL => SIGKILL,
-            Signal::SIGTERM => SIGTERM,
-            Signal::SIGINT => SIGINT,
-            Signal::SIGHUP => SIGHUP,
-            Signal::SIGSTOP => SIGSTOP,
-            Signal::SIGCONT => SIGCONT,
-            Signal::SIGCHLD => SIGCHLD,
-            Signal::SIGUSR1 => SIGUSR1,
-            Signal::SIGUSR2 => SIGUSR2,
-            _ => panic!("unsupported signal: {:?}", self),
-        }
+        // The variants of nix's Signal are the libc constants themselves.
+        self as c_int
     }
 }
 
@@ -79,7 +68,7 @@ pub fn new(signal_name: Option<String>) -> Option<Signal> {
     }
 }
 
-static GLOBAL_HANDLER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static GLOBAL_HANDLER_ID: AtomicUsize = AtomicUsize::new(0);
 
 #[cfg(unix)]
 #[allow(clippy::unnecessary_operation)]
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::{command_arg, parse_spec_lines, spec_pipeline, CommandArg, CommandError, SpecLines, Steps};
+use crate::pipeline::Pipeline;
+use crate::sequence::{self, Sequence};
+use crate::template::Template;
+
+/// A spec kept in a file, usually with the `.cmdspec` extension.
+///
+/// The file holds the same text as the string given to `command!`. Values
+/// come from named parameters, written as `{name}` in the file and set with
+/// `param`; `{{` and `}}` stand for literal braces. Errors name the file
+/// and, where it can be told, the line.
+#[derive(Debug, Clone)]
+pub struct SpecFile {
+    path: PathBuf,
+    text: String,
+    params: HashMap<String, CommandArg>,
+}
+
+impl SpecFile {
+    /// Reads the spec from `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SpecFile, CommandError> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(SpecFile::new(path, text)),
+            Err(err) => Err(CommandError::SpecFile(path.to_owned(), None, Box::new(CommandError::Io(err, None)))),
+        }
+    }
+
+    /// Uses `text` as the spec, such as one embedded with `include_str!`.
+    /// `path` is only used in errors.
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(path: P, text: S) -> SpecFile {
+        SpecFile {
+            path: path.into(),
+            text: text.into(),
+            params: HashMap::new(),
+        }
+    }
+
+    /// Sets the value of `{name}`. Values are converted like the arguments of `command!`.
+    pub fn param<'a, T>(mut self, name: &str, value: &'a T) -> SpecFile
+    where
+        CommandArg: From<&'a T>,
+    {
+        self.params.insert(name.to_string(), command_arg(value));
+        self
+    }
+
+    /// Builds a single `Command`, like `commandify`.
+    pub fn command(&self) -> Result<Command, CommandError> {
+        let (steps, lines) = self.parse()?;
+        if steps.len() > 1 {
+            return self.in_file(lines.stages[1].first().copied(), Err(CommandError::UnexpectedSequence));
+        }
+        let specs = &steps[0].1;
+        if specs.len() > 1 {
+            return self.in_file(lines.stages[0].get(1).copied(), Err(CommandError::UnexpectedPipe));
+        }
+        if specs[0].timeout.is_some() {
+            return self.in_file(lines.timeout, Err(CommandError::UnexpectedTimeout));
+        }
+        specs[0]
+            .to_command()
+            .map_err(|err| CommandError::SpecFile(self.path.clone(), lines.blame(0, 0, &err), Box::new(err)))
+    }
+
+    /// Builds a `Pipeline`, like `pipelinify`.
+    pub fn pipeline(&self) -> Result<Pipeline, CommandError> {
+        let (steps, lines) = self.parse()?;
+        if steps.len() > 1 {
+            return self.in_file(lines.stages[1].first().copied(), Err(CommandError::UnexpectedSequence));
+        }
+        spec_pipeline(&steps[0].1).map_err(|(index, err)| {
+            CommandError::SpecFile(self.path.clone(), lines.blame(0, index, &err), Box::new(err))
+        })
+    }
+
+    /// Builds a `Sequence`, like `sequencify`. Its steps are only built when
+    /// it runs, so errors from that don't name the file.
+    pub fn sequence(&self) -> Result<Sequence, CommandError> {
+        let (steps, _) = self.parse()?;
+        Ok(sequence::from_specs(steps))
+    }
+
+    // Parses the whole file once; the parser tells which line it failed on.
+    fn parse(&self) -> Result<(Steps, SpecLines), CommandError> {
+        parse_spec_lines(self.template()?)
+            .map_err(|(line, err)| CommandError::SpecFile(self.path.clone(), line, Box::new(err)))
+    }
+
+    fn in_file<T>(&self, line: Option<usize>, result: Result<T, CommandError>) -> Result<T, CommandError> {
+        result.map_err(|err| CommandError::SpecFile(self.path.clone(), line, Box::new(err)))
+    }
+
+    // Builds a template from the file, with the values of the parameters.
+    fn template(&self) -> Result<Template, CommandError> {
+        let mut template = Template::new();
+        for (index, line) in self.text.split_inclusive('\n').enumerate() {
+            let invalid = |name: &str| {
+                CommandError::SpecFile(
+                    self.path.clone(),
+                    Some(index + 1),
+                    Box::new(CommandError::InvalidParameter(name.to_string())),
+                )
+            };
+            let mut rest = line;
+            while let Some(start) = rest.find(['{', '}']) {
+                let (text, tail) = rest.split_at(start);
+                template = template.text(text);
+                if tail.starts_with("{{") || tail.starts_with("}}") {
+                    template = template.text(&tail[..1]);
+                    rest = &tail[2..];
+                    continue;
+                }
+                let end = match tail.find('}') {
+                    Some(end) if tail.starts_with('{') => end,
+                    _ => return Err(invalid(tail.trim_end())),
+                };
+                let name = &tail[1..end];
+                let value = self.params.get(name).ok_or_else(|| invalid(name))?;
+                template = template.arg(value.clone());
+                rest = &tail[end + 1..];
+            }
+            template = template.text(rest);
+        }
+        Ok(template)
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+
+use super::lines::read_stream;
+use super::{dry_run, executor, exit_status_result, io_error, CommandContext, CommandError, Stream};
+
+/// Where `execute_tee` copies a command's output to, besides the terminal.
+///
+/// Any `Write` works as a sink, such as a `File` or a `&mut Vec<u8>`.
+/// Sinks added with `both` get stdout and stderr interleaved in the order
+/// they were read.
+#[derive(Default)]
+pub struct Tee<'a> {
+    sinks: Vec<(Option<Stream>, Box<dyn Write + 'a>)>,
+}
+
+impl<'a> Tee<'a> {
+    pub fn new() -> Tee<'a> {
+        Tee::default()
+    }
+
+    /// Also copies stdout to `sink`.
+    pub fn stdout<W: Write + 'a>(mut self, sink: W) -> Tee<'a> {
+        self.sinks.push((Some(Stream::Stdout), Box::new(sink)));
+        self
+    }
+
+    /// Also copies stderr to `sink`.
+    pub fn stderr<W: Write + 'a>(mut self, sink: W) -> Tee<'a> {
+        self.sinks.push((Some(Stream::Stderr), Box::new(sink)));
+        self
+    }
+
+    /// Also copies both streams to `sink`.
+    pub fn both<W: Write + 'a>(mut self, sink: W) -> Tee<'a> {
+        self.sinks.push((None, Box::new(sink)));
+        self
+    }
+}
+
+/// Runs `command`, writing its output to our own stdout and stderr and to
+/// the sinks of `tee` as it arrives. All sinks are flushed before returning.
+pub fn execute_tee(mut command: Command, mut tee: Tee) -> Result<(), CommandError> {
+    let context = CommandContext::new(&command);
+    if dry_run::intercept(::std::slice::from_ref(&context)) {
+        return Ok(());
+    }
+    if let Some(executor) = executor::current() {
+        let output = executor::run_one(&*executor, command, None, None).map_err(io_error(&context))?;
+        let mut write_error = None;
+        for (stream, chunk) in [(Stream::Stdout, output.stdout), (Stream::Stderr, output.stderr)] {
+            write_chunk(&mut tee, &mut write_error, stream, &chunk);
+        }
+        return finish(tee, write_error, output.status, context);
+    }
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io_error(&context))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let readers = vec![
+        read_stream(child.stdout.take().unwrap(), Stream::Stdout, false, sender.clone()),
+        read_stream(child.stderr.take().unwrap(), Stream::Stderr, false, sender),
+    ];
+
+    // A failed write doesn't stop the copying, so the command never blocks
+    // on a full pipe. The first write error is reported once it exits.
+    let mut write_error = None;
+    for (stream, chunk) in receiver {
+        write_chunk(&mut tee, &mut write_error, stream, &chunk);
+    }
+    for reader in readers {
+        record(&mut write_error, reader.join().unwrap());
+    }
+
+    let status = child.wait().map_err(io_error(&context))?;
+    finish(tee, write_error, status, context)
+}
+
+// Writes a chunk of `stream` to the terminal and to the sinks that want it.
+fn write_chunk(tee: &mut Tee, write_error: &mut Option<io::Error>, stream: Stream, chunk: &[u8]) {
+    let result = match stream {
+        Stream::Stdout => write_flushed(&mut io::stdout(), chunk),
+        Stream::Stderr => write_flushed(&mut io::stderr(), chunk),
+    };
+    record(write_error, result);
+    for (filter, sink) in &mut tee.sinks {
+        if filter.is_none_or(|filter| filter == stream) {
+            record(write_error, sink.write_all(chunk));
+        }
+    }
+}
+
+// Flushes the sinks, then reports a failed command before a failed write.
+fn finish(
+    mut tee: Tee,
+    mut write_error: Option<io::Error>,
+    status: ExitStatus,
+    context: CommandContext,
+) -> Result<(), CommandError> {
+    for (_, sink) in &mut tee.sinks {
+        record(&mut write_error, sink.flush());
+    }
+    exit_status_result(status, context.clone())?;
+    match write_error {
+        Some(err) => Err(io_error(&context)(err)),
+        None => Ok(()),
+    }
+}
+
+fn write_flushed<W: Write>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(chunk)?;
+    writer.flush()
+}
+
+fn record(first: &mut Option<io::Error>, result: io::Result<()>) {
+    if let Err(err) = result {
+        first.get_or_insert(err);
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use super::CommandArg;
+
+#[derive(Debug)]
+pub enum Segment {
+    Text(String),
+    Arg(CommandArg),
+}
+
+/// A command spec made of literal text and interpolated values.
+///
+/// Only the text is parsed as spec syntax. Each value is inserted into the
+/// word it appears in as it is, without being quoted and split again, so
+/// arguments can't be corrupted by the round trip.
+///
+/// `command!` and friends build one of these from their template; a plain
+/// `&str` converts into a template that is all text.
+#[derive(Debug, Default)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn new() -> Template {
+        Template::default()
+    }
+
+    /// Appends literal spec text.
+    pub fn text(mut self, text: &str) -> Template {
+        if let Some(Segment::Text(last)) = self.segments.last_mut() {
+            last.push_str(text);
+            return self;
+        }
+        self.segments.push(Segment::Text(text.to_string()));
+        self
+    }
+
+    /// Appends a value, which becomes part of the word it appears in.
+    pub fn arg(mut self, arg: CommandArg) -> Template {
+        self.segments.push(Segment::Arg(arg));
+        self
+    }
+
+    pub(crate) fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+}
+
+/// Renders the template with every value quoted by `CommandArg`'s `Display`.
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in &self.segments {
+            match *segment {
+                Segment::Text(ref text) => write!(f, "{}", text)?,
+                Segment::Arg(ref arg) => write!(f, "{}", arg)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for Template {
+    fn from(value: &str) -> Self {
+        Template::new().text(value)
+    }
+}
+
+impl From<&String> for Template {
+    fn from(value: &String) -> Self {
+        Template::new().text(value)
+    }
+}
+
+impl From<String> for Template {
+    fn from(value: String) -> Self {
+        Template::new().text(&value)
+    }
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::process::Process;
+use crate::signal::Signal;
+
+// How often running commands are checked while a timeout is pending.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits how long a command may run.
+///
+/// When the time is up, `signal` is sent to the command's process group.
+/// Whatever is still running after the grace period is killed with SIGKILL.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    duration: Duration,
+    signal: Signal,
+    grace: Duration,
+}
+
+impl Timeout {
+    /// Sends SIGTERM after `duration`, and SIGKILL 5 seconds later.
+    pub fn new(duration: Duration) -> Timeout {
+        Timeout {
+            duration,
+            signal: Signal::SIGTERM,
+            grace: Duration::from_secs(5),
+        }
+    }
+
+    /// The signal sent first when the time is up.
+    pub fn signal(mut self, signal: Signal) -> Timeout {
+        self.signal = signal;
+        self
+    }
+
+    /// How long to wait after the first signal before sending SIGKILL.
+    pub fn grace(mut self, grace: Duration) -> Timeout {
+        self.grace = grace;
+        self
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    // The same timeout with a different duration, for what's left of a sequence.
+    pub(crate) fn with_duration(mut self, duration: Duration) -> Timeout {
+        self.duration = duration;
+        self
+    }
+}
+
+pub enum Outcome {
+    Exited(Vec<ExitStatus>),
+    /// How long the processes had run, and the index of the first one that was still running.
+    TimedOut(Duration, usize),
+}
+
+/// Waits for all `processes` to exit, started at `start`, stopping them if
+/// the timeout runs out. It only counts as timed out if the signal reached a
+/// process that was still running.
+pub fn wait(processes: &[&Process], timeout: &Timeout, start: Instant) -> io::Result<Outcome> {
+    let mut outcome = None;
+    if !poll_until(processes, start + timeout.duration)? {
+        let running = processes.iter().map(|process| process.signal(timeout.signal)).collect::<Vec<_>>();
+        if let Some(index) = running.iter().position(|&running| running) {
+            outcome = Some(Outcome::TimedOut(start.elapsed(), index));
+        }
+        kill_after_grace(processes, timeout)?;
+    }
+    let statuses = processes.iter().map(|process| process.status()).collect::<io::Result<_>>()?;
+    Ok(outcome.unwrap_or(Outcome::Exited(statuses)))
+}
+
+// Kills the processes that outlive the grace period after the first signal.
+// Doesn't wait for them to exit after that.
+fn kill_after_grace(processes: &[&Process], timeout: &Timeout) -> io::Result<()> {
+    if poll_until(processes, Instant::now() + timeout.grace)? {
+        return Ok(());
+    }
+    for process in processes {
+        process.signal(Signal::SIGKILL);
+    }
+    Ok(())
+}
+
+/// Enforces `timeout`, counted from `start`, on a process in the background,
+/// and records in `timed_out` how long it ran if it had to be stopped. Gives
+/// up once the process exits or is dropped.
+pub fn watch(process: Weak<Process>, timeout: Timeout, start: Instant, timed_out: Arc<Mutex<Option<Duration>>>) {
+    thread::spawn(move || loop {
+        let process = match process.upgrade() {
+            Some(process) => process,
+            None => return,
+        };
+        // Doesn't reap the command; that is left to whoever waits for it.
+        match process.exited() {
+            Ok(false) => {}
+            _ => return,
+        }
+        if start.elapsed() >= timeout.duration {
+            // Signalling under the lock means nobody sees the command exit
+            // before it is known whether the timeout stopped it.
+            let mut stopped = timed_out.lock().unwrap();
+            if process.signal(timeout.signal) {
+                *stopped = Some(start.elapsed());
+            }
+            drop(stopped);
+            let _ = kill_after_grace(&[&process], &timeout);
+            return;
+        }
+        drop(process);
+        thread::sleep(POLL_INTERVAL);
+    });
+}
+
+// Returns whether all processes exited before the deadline.
+fn poll_until(processes: &[&Process], deadline: Instant) -> io::Result<bool> {
+    loop {
+        let mut done = true;
+        for process in processes {
+            done &= process.try_status()?.is_some();
+        }
+        if done {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Parses a duration such as `30`, `30s`, `1.5s`, `500ms`, `2m` or `1h`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|ch: char| !ch.is_ascii_digit() && ch != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("-1"), None);
+    }
+}
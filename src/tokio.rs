@@ -0,0 +1,157 @@
+//! Async versions of `CommandSpecExt` and `SpawnGuard`, for use on a tokio
+//! runtime. Enabled by the `tokio` feature, which also adds
+//! `Pipeline::execute_async` and `Sequence::execute_async`.
+//!
+//! Import `AsyncCommandSpecExt` instead of `CommandSpecExt` where you need the
+//! async methods; their names are the same.
+
+use std::future::Future;
+use std::io;
+use std::process::{Command, ExitStatus, Output};
+use std::slice;
+use std::sync::Arc;
+
+use crate::{
+    dry_run, executor, exit_status_result, io_error, timeout, CommandContext, CommandError, CommandSpecExt, DropPolicy,
+    Executor, Signal,
+};
+
+pub trait AsyncCommandSpecExt {
+    /// Like `CommandSpecExt::execute`, but waits for the command without blocking the runtime.
+    fn execute(self) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    /// Like `CommandSpecExt::execute_output`.
+    fn execute_output(self) -> impl Future<Output = Result<Output, CommandError>> + Send;
+
+    /// Like `CommandSpecExt::execute_stdout`.
+    fn execute_stdout(self) -> impl Future<Output = Result<String, CommandError>> + Send;
+
+    /// Like `CommandSpecExt::execute_stderr`.
+    fn execute_stderr(self) -> impl Future<Output = Result<String, CommandError>> + Send;
+
+    /// Like `CommandSpecExt::scoped_spawn`: starts the command in its own process group.
+    fn scoped_spawn(self) -> Result<SpawnGuard, io::Error>;
+}
+
+impl AsyncCommandSpecExt for Command {
+    async fn execute(self) -> Result<(), CommandError> {
+        let context = CommandContext::new(&self);
+        if dry_run::intercept(slice::from_ref(&context)) {
+            return Ok(());
+        }
+        if let Some(executor) = executor::current() {
+            let output = run_blocking(executor, self).await.map_err(io_error(&context))?;
+            return exit_status_result(output.status, context);
+        }
+        let mut child = tokio::process::Command::from(self).spawn().map_err(io_error(&context))?;
+        let status = child.wait().await.map_err(io_error(&context))?;
+        exit_status_result(status, context)
+    }
+
+    async fn execute_output(self) -> Result<Output, CommandError> {
+        let context = CommandContext::new(&self);
+        if dry_run::intercept(slice::from_ref(&context)) {
+            return Ok(Output { status: ExitStatus::default(), stdout: vec![], stderr: vec![] });
+        }
+        let output = match executor::current() {
+            Some(executor) => run_blocking(executor, self).await,
+            None => tokio::process::Command::from(self).output().await,
+        };
+        let output = output.map_err(io_error(&context))?;
+        match exit_status_result(output.status, context.with_stderr(&output.stderr)) {
+            Ok(()) => Ok(output),
+            Err(err) => Err(CommandError::Output(Box::new(err), output)),
+        }
+    }
+
+    async fn execute_stdout(self) -> Result<String, CommandError> {
+        let output = AsyncCommandSpecExt::execute_output(self).await?;
+        String::from_utf8(output.stdout).map_err(CommandError::Utf8)
+    }
+
+    async fn execute_stderr(self) -> Result<String, CommandError> {
+        let output = AsyncCommandSpecExt::execute_output(self).await?;
+        String::from_utf8(output.stderr).map_err(CommandError::Utf8)
+    }
+
+    fn scoped_spawn(self) -> Result<SpawnGuard, io::Error> {
+        CommandSpecExt::scoped_spawn(self).map(|guard| SpawnGuard { guard: Some(guard) })
+    }
+}
+
+// Executors block, so they run on a blocking task of the runtime.
+async fn run_blocking(executor: Arc<dyn Executor>, command: Command) -> io::Result<Output> {
+    tokio::task::spawn_blocking(move || executor::run_one(&*executor, command, None, None))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// The async counterpart of `SpawnGuard`. It wraps a blocking guard, so the
+/// command is cleaned up on Ctrl-C the same way, and only waiting differs.
+///
+/// With `DropPolicy::Terminate`, dropping it hands the grace period to a
+/// blocking task of the current runtime instead of sleeping. `DropPolicy::Wait`
+/// still blocks the thread it is dropped on until the command exits.
+pub struct SpawnGuard {
+    // Only taken out when the guard is dropped.
+    guard: Option<crate::SpawnGuard>,
+}
+
+impl SpawnGuard {
+    /// Sets what happens to the command when this guard is dropped.
+    pub fn drop_policy(mut self, policy: DropPolicy) -> SpawnGuard {
+        self.guard = self.guard.take().map(|guard| guard.drop_policy(policy));
+        self
+    }
+
+    /// The id of the process group, which is the pid of the command on Unix.
+    pub fn id(&self) -> i32 {
+        self.guard().id()
+    }
+
+    /// Sends `signal` to the whole process group.
+    pub fn signal(&self, signal: Signal) {
+        self.guard().signal(signal);
+    }
+
+    /// The exit status, if the command has exited.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.guard().exit_status()
+    }
+
+    /// Returns `None` while the command is running, and then the same result as `execute`.
+    pub fn try_wait(&self) -> Option<Result<(), CommandError>> {
+        self.guard().try_wait()
+    }
+
+    /// Waits for the command to exit and returns the same result as `execute`.
+    pub async fn wait(&self) -> Result<(), CommandError> {
+        // Polls like the blocking guard, so the leader is only reaped by us.
+        loop {
+            if let Some(result) = self.try_wait() {
+                return result;
+            }
+            tokio::time::sleep(timeout::POLL_INTERVAL).await;
+        }
+    }
+
+    fn guard(&self) -> &crate::SpawnGuard {
+        self.guard.as_ref().expect("guard is only taken on drop")
+    }
+}
+
+impl Drop for SpawnGuard {
+    fn drop(&mut self) {
+        let guard = match self.guard.take() {
+            Some(guard) => guard,
+            None => return,
+        };
+        if let DropPolicy::Terminate(_) = guard.drop_policy {
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                handle.spawn_blocking(move || drop(guard));
+                return;
+            }
+        }
+        drop(guard);
+    }
+}
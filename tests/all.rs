@@ -49,3 +49,1351 @@ fn cmd_rustc() {
     ).unwrap().output().unwrap();
     assert!(res.stdout.starts_with(b"rustc "));
 }
+
+#[cfg(not(windows))]
+mod pipe {
+    use tb2f_commandspec::CommandError;
+
+    #[test]
+    fn pipe_success() {
+        execute!(r"echo {a} | grep -q SENTINEL", a = "SENTINEL").unwrap();
+    }
+
+    #[test]
+    fn pipe_stage_failure() {
+        match execute!(r"echo SENTINEL | grep -q MISSING | cat").unwrap_err() {
+            CommandError::Stage(index, ref err) => {
+                assert_eq!(index, 1);
+                assert_eq!(err.error_code(), 1);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn pipe_quoted_is_literal() {
+        let res = command!(r"echo '|' {a}", a = "|").unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"| |\n");
+    }
+
+    #[test]
+    fn pipe_rejected_by_command() {
+        match tb2f_commandspec::commandify(r"echo a | cat") {
+            Err(CommandError::UnexpectedPipe) => {}
+            _ => panic!("expected UnexpectedPipe"),
+        }
+        match pipeline!(r"echo a | | cat") {
+            Err(CommandError::EmptyPipeStage) => {}
+            _ => panic!("expected EmptyPipeStage"),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod redirect {
+    use std::fs;
+    use std::path::PathBuf;
+    use tb2f_commandspec::CommandError;
+
+    fn temp_path(name: &str) -> String {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("commandspec-{}-{}", ::std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn redirect_write_append_read() {
+        let out = temp_path("out with space");
+        execute!(r"echo {a} > {out}", a = "first", out = out).unwrap();
+        execute!(r"echo second >>{out}", out = out).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "first\nsecond\n");
+
+        let res = command!(r"cat < {out}", out = out).unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"first\nsecond\n");
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn redirect_stderr() {
+        let err = temp_path("err");
+        execute!(r"sh -c {script} 2> {err}", script = "echo oops >&2", err = err).unwrap();
+        assert_eq!(fs::read_to_string(&err).unwrap(), "oops\n");
+        fs::remove_file(&err).unwrap();
+
+        execute!(r"sh -c {script} 2>&1 | grep -q oops", script = "echo oops >&2").unwrap();
+    }
+
+    #[test]
+    fn redirect_unopenable() {
+        match execute!(r"echo a > /nonexistent/commandspec/out") {
+            Err(CommandError::Redirect(path, _)) => {
+                assert_eq!(path, PathBuf::from("/nonexistent/commandspec/out"));
+            }
+            _ => panic!("expected Redirect"),
+        }
+        match tb2f_commandspec::commandify(r"echo a >") {
+            Err(CommandError::InvalidRedirect) => {}
+            _ => panic!("expected InvalidRedirect"),
+        }
+        // An interpolated file name is a single word.
+        let files = vec![temp_path("o1"), temp_path("o2")];
+        match command!(r"echo hi > {files}", files = files) {
+            Err(CommandError::InvalidRedirect) => {}
+            _ => panic!("expected InvalidRedirect"),
+        }
+        match command!(r"echo hi > {file} next", file = None::<String>) {
+            Err(CommandError::InvalidRedirect) => {}
+            _ => panic!("expected InvalidRedirect"),
+        }
+        assert!(!PathBuf::from(&files[0]).exists());
+    }
+
+    #[test]
+    fn redirect_opened_on_spawn() {
+        let out = temp_path("kept");
+        fs::write(&out, "kept\n").unwrap();
+        let command = command!(r"echo replaced > {out}", out = out).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "kept\n");
+        drop(command);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "kept\n");
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn redirect_stderr_to_captured_stdout() {
+        let res = command!(r"sh -c {script} 2>&1", script = "echo out; echo err >&2")
+            .unwrap()
+            .output()
+            .unwrap();
+        assert_eq!(res.stdout, b"out\nerr\n");
+        assert!(res.stderr.is_empty());
+    }
+}
+
+#[cfg(not(windows))]
+mod sequence {
+    use std::fs;
+    use tb2f_commandspec::CommandError;
+
+    #[test]
+    fn sequence_short_circuit() {
+        execute!(r"true && true; false || true").unwrap();
+        execute!(r"false && exit_if_run_{a}; true", a = "never").unwrap();
+
+        match execute!(r"true && false && true") {
+            Err(CommandError::Step(index, ref err)) => {
+                assert_eq!(index, 1);
+                assert_eq!(err.error_code(), 1);
+            }
+            _ => panic!("expected Step"),
+        }
+        assert_eq!(execute!(r"true; sh -c 'exit 3'").unwrap_err().error_code(), 3);
+    }
+
+    #[test]
+    fn sequence_redirect_after_mkdir() {
+        let mut dir = ::std::env::temp_dir();
+        dir.push(format!("commandspec-{}-seq", ::std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        execute!(
+            r"
+                mkdir -p {dir} && echo {a} > {dir}/out; cat {dir}/out | grep -q {a}
+            ",
+            dir = dir,
+            a = "SENTINEL",
+        ).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sequence_rejected_elsewhere() {
+        match pipeline!(r"true && true") {
+            Err(CommandError::UnexpectedSequence) => {}
+            _ => panic!("expected UnexpectedSequence"),
+        }
+        match sequence!(r"true && ; true") {
+            Err(CommandError::EmptySequenceStep) => {}
+            _ => panic!("expected EmptySequenceStep"),
+        }
+        assert_eq!(sequence!(r"true; true;").unwrap().len(), 2);
+    }
+}
+
+#[cfg(not(windows))]
+mod checked {
+    use tb2f_commandspec::{checked_command, CommandError};
+
+    #[test]
+    fn checked_args_are_not_resplit() {
+        let args = vec!["a b", "c"];
+        let res = checked_command!(
+            r#"
+                export A={a}
+                sh -c 'echo "$A|$0|$1|$2|$3"' {args} --x={flag}"y" {none}
+            "#,
+            a = "one two",
+            args = args,
+            flag = Some("$HOME"),
+            none = None::<String>,
+        ).unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"one two|a b|c|--x=$HOMEy|\n");
+    }
+
+    #[test]
+    fn checked_cd() {
+        let res = checked_command!(
+            r"
+                cd {dir}
+                pwd
+            ",
+            dir = "/",
+        ).unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"/\n");
+    }
+
+    #[test]
+    fn checked_builder_parts() {
+        let tool = "printf";
+        let cmd = checked_command!("export A=1 B={b}\n{tool} [%s] x", b = "2").unwrap();
+        assert_eq!(cmd.get_program(), "printf");
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["[%s]", "x"]);
+        let mut envs = cmd.get_envs().collect::<Vec<_>>();
+        envs.sort();
+        assert_eq!(envs, [("A".as_ref(), Some("1".as_ref())), ("B".as_ref(), Some("2".as_ref()))]);
+
+        match checked_command!("cd {dir}\npwd", dir = vec!["/", "/tmp"]) {
+            Err(CommandError::TooManyCDArgs(1, 2)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match checked_command!("export {item}\ntrue", item = "NOVALUE") {
+            Err(CommandError::InvalidExport) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod capture {
+    use tb2f_commandspec::checked_command;
+
+    #[test]
+    fn capture_implicit_and_positional() {
+        let file = "a b";
+        let list = vec!["c", "d"];
+        let res = command!(r"printf [%s] {file} {} {0} {list} {x}", "e f", x = 1)
+            .unwrap()
+            .output()
+            .unwrap();
+        assert_eq!(res.stdout, b"[a b][e f][e f][c][d][1]".to_vec());
+
+        let res = checked_command!(r"printf [%s] {file} {}", "e f").unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"[a b][e f]".to_vec());
+
+        // Like in `format!`, an index can refer to a named argument.
+        let res = command!(r"printf [%s] {1} {0}", "g", h = "i").unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"[i][g]".to_vec());
+    }
+
+    #[test]
+    fn capture_execute() {
+        let code = 7;
+        assert_eq!(sh_execute!(r"exit {code}").unwrap_err().error_code(), 7);
+        let word = "SENTINEL";
+        execute!(r"echo {word} | grep -q {}", word).unwrap();
+    }
+
+    #[test]
+    fn capture_commas_in_arguments() {
+        use std::collections::HashMap;
+
+        let res = command!("echo {x}", x = HashMap::<i32, i32>::new().len() as i32).unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"0\n".to_vec());
+        let res = checked_command!("echo {}", HashMap::<i32, i32>::new().len() as i32).unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"0\n".to_vec());
+    }
+}
+
+#[cfg(not(windows))]
+mod template {
+    use tb2f_commandspec::{commandify, CommandArg, Template};
+
+    #[test]
+    fn template_values_are_not_reparsed() {
+        let value = r#"it's "quoted" \ $HOME `x` | > ; #"#;
+        let res = command!(r"printf [%s] {value} x{value}", value = value).unwrap().output().unwrap();
+        assert_eq!(res.stdout, format!("[{0}][x{0}]", value).into_bytes());
+
+        let template = Template::new()
+            .text("printf [%s] ")
+            .arg(CommandArg::Literal(value.to_string()))
+            .text(" '")
+            .arg(CommandArg::List(vec!["a".into(), "b c".into()]))
+            .text("'");
+        let res = commandify(template).unwrap().output().unwrap();
+        assert_eq!(res.stdout, format!("[{}][a][b c]", value).into_bytes());
+    }
+}
+
+#[cfg(unix)]
+mod os_string {
+    use std::ffi::{OsStr, OsString};
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    #[test]
+    fn os_string_bytes_survive() {
+        let value = OsStr::from_bytes(b"a \xff'b");
+        let path = PathBuf::from(value);
+        let owned = OsString::from(value);
+        let res = command!(r"printf [%s] {value} {path} x{owned}").unwrap().output().unwrap();
+        assert_eq!(res.stdout, b"[a \xff'b][a \xff'b][xa \xff'b]".to_vec());
+    }
+
+    #[test]
+    fn os_string_cd() {
+        let mut dir = ::std::env::temp_dir();
+        dir.push(OsStr::from_bytes(b"commandspec-\xff-cd"));
+        fs::create_dir_all(&dir).unwrap();
+        let res = command!("cd {dir}\npwd").unwrap().output().unwrap();
+        fs::remove_dir(&dir).unwrap();
+        assert!(res.stdout.ends_with(b"commandspec-\xff-cd\n"));
+    }
+}
+
+#[cfg(not(windows))]
+mod output {
+    use tb2f_commandspec::{CommandError, CommandSpecExt};
+
+    #[test]
+    fn output_captured() {
+        let output = command!("sh -c 'echo out; echo err >&2'").unwrap().execute_output().unwrap();
+        assert_eq!(output.stdout, b"out\n".to_vec());
+        assert_eq!(output.stderr, b"err\n".to_vec());
+        assert_eq!(command!("echo {}", "a b").unwrap().execute_stdout().unwrap(), "a b\n");
+        assert_eq!(command!("sh -c 'echo x >&2'").unwrap().execute_stderr().unwrap(), "x\n");
+    }
+
+    #[test]
+    fn output_failure_keeps_stderr() {
+        let err = command!("sh -c 'echo broken >&2; exit 3'").unwrap().execute_stdout().unwrap_err();
+        assert_eq!(err.error_code(), 3);
+        match err {
+            CommandError::Output(_, ref output) => assert_eq!(output.stderr, b"broken\n".to_vec()),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert!(err.to_string().ends_with("stderr:\nbroken"));
+    }
+}
+
+#[cfg(not(windows))]
+mod context {
+    use tb2f_commandspec::{CommandError, CommandSpecExt};
+
+    #[test]
+    fn context_in_errors() {
+        let err = execute!("cd /\nexport MODE=fast\nsh -c {} x 'a b'", "exit 4").unwrap_err();
+        match err {
+            CommandError::Code(4, ref context) => {
+                assert_eq!(context.program, "sh");
+                assert_eq!(context.args.len(), 4);
+                assert_eq!(context.cwd.as_ref().unwrap().to_str(), Some("/"));
+            }
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Command failed with error code 4 (running `sh -c \"exit 4\" x \"a b\"` in / with MODE=fast)"
+        );
+
+        let err = command!("no-such-binary-for-commandspec").unwrap().execute().unwrap_err();
+        assert!(err.to_string().contains("(running `no-such-binary-for-commandspec` in "));
+    }
+}
+
+#[cfg(unix)]
+mod signaled {
+    use tb2f_commandspec::{CommandError, Signal};
+
+    #[test]
+    fn signaled_names_signal() {
+        let err = execute!(r"sh -c 'kill -KILL $$'").unwrap_err();
+        match err {
+            CommandError::Signaled(Signal::SIGKILL, false, _) => {}
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert!(err.to_string().starts_with("Command was killed by SIGKILL (running `sh -c"));
+    }
+}
+
+#[cfg(unix)]
+mod timeout {
+    use std::time::{Duration, Instant};
+    use tb2f_commandspec::{CommandError, CommandSpecExt, Signal, Timeout};
+
+    #[test]
+    fn timeout_stops_command() {
+        let start = Instant::now();
+        let err = command!("sleep 10")
+            .unwrap()
+            .execute_timeout(Timeout::new(Duration::from_millis(100)))
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match err {
+            CommandError::Timeout(elapsed, ref context) => {
+                assert!(elapsed >= Duration::from_millis(100));
+                assert_eq!(context.program, "sleep");
+            }
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert!(err.to_string().starts_with("Command timed out after 0."));
+
+        command!("true").unwrap().execute_timeout(Timeout::new(Duration::from_secs(5))).unwrap();
+    }
+
+    #[test]
+    fn timeout_escalates_to_kill() {
+        let start = Instant::now();
+        let timeout = Timeout::new(Duration::from_millis(100))
+            .signal(Signal::SIGUSR1)
+            .grace(Duration::from_millis(100));
+        let res = command!(r"sh -c 'trap {} USR1; sleep 10 & wait; sleep 10'", "")
+            .unwrap()
+            .execute_timeout(timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match res {
+            Err(CommandError::Timeout(..)) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+    }
+
+    #[test]
+    fn timeout_directive() {
+        let start = Instant::now();
+        match execute!("timeout 500ms\nsleep 0.2 && sleep 10") {
+            Err(CommandError::Step(1, ref err)) => match **err {
+                CommandError::Timeout(elapsed, _) => assert!(elapsed >= Duration::from_millis(500)),
+                ref other => panic!("unexpected error: {}", other),
+            },
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        execute!("timeout 5s\ntrue").unwrap();
+        execute!("timeout 1 true").unwrap();
+        match execute!("timeout soon\ntrue") {
+            Err(CommandError::InvalidTimeout) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+        match tb2f_commandspec::commandify("timeout 1s\ntrue") {
+            Err(CommandError::UnexpectedTimeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod spawn_guard {
+    use std::time::Duration;
+    use tb2f_commandspec::{CommandError, CommandSpecExt, Signal, Timeout};
+
+    #[test]
+    fn spawn_guard_wait() {
+        let guard = command!("sleep 0.2").unwrap().scoped_spawn().unwrap();
+        assert!(guard.id() > 0);
+        assert!(guard.try_wait().is_none());
+        assert!(guard.exit_status().is_none());
+        guard.wait().unwrap();
+        assert!(guard.exit_status().unwrap().success());
+
+        let guard = command!("sh -c 'exit 3'").unwrap().scoped_spawn().unwrap();
+        assert_eq!(guard.wait().unwrap_err().error_code(), 3);
+    }
+
+    #[test]
+    fn spawn_guard_signal_and_timeout() {
+        let guard = command!("sleep 10").unwrap().scoped_spawn().unwrap();
+        guard.signal(Signal::SIGTERM);
+        match guard.wait() {
+            Err(CommandError::Signaled(Signal::SIGTERM, _, _)) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+
+        // Not one of the signals the crate handles itself.
+        let guard = command!("sleep 10").unwrap().scoped_spawn().unwrap();
+        guard.signal(Signal::SIGQUIT);
+        match guard.wait() {
+            Err(CommandError::Signaled(Signal::SIGQUIT, _, _)) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+
+        let guard = command!("sleep 10")
+            .unwrap()
+            .scoped_spawn_timeout(Timeout::new(Duration::from_millis(100)))
+            .unwrap();
+        match guard.wait() {
+            Err(CommandError::Timeout(..)) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|err| err.to_string())),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod drop_policy {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use tb2f_commandspec::{CommandSpecExt, DropPolicy};
+
+    // Whether a process is still running, as opposed to gone or a zombie.
+    fn running(pid: &str) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.rsplit(") ").next().unwrap().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn drop_policy_terminate_kills_group() {
+        let guard = command!(r"sh -c 'trap {} TERM; sleep 30 & wait'", "")
+            .unwrap()
+            .scoped_spawn()
+            .unwrap()
+            .drop_policy(DropPolicy::Terminate(Duration::from_millis(100)));
+        ::std::thread::sleep(Duration::from_millis(200));
+        let pid = fs::read_to_string(format!("/proc/{}/task/{0}/children", guard.id())).unwrap();
+        let pid = pid.trim().to_string();
+        assert!(running(&pid));
+
+        let start = Instant::now();
+        drop(guard);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        ::std::thread::sleep(Duration::from_millis(100));
+        assert!(!running(&pid));
+    }
+
+    #[test]
+    fn drop_policy_terminate_after_leader_exits() {
+        // The shell exits on SIGTERM, but leaves a subshell behind that ignores it.
+        let guard = command!(r#"sh -c "(trap '' TERM; sleep 30) & wait""#)
+            .unwrap()
+            .scoped_spawn()
+            .unwrap()
+            .drop_policy(DropPolicy::Terminate(Duration::from_secs(5)));
+        ::std::thread::sleep(Duration::from_millis(200));
+        let pid = fs::read_to_string(format!("/proc/{}/task/{0}/children", guard.id())).unwrap();
+        let pid = pid.trim().to_string();
+        assert!(running(&pid));
+
+        let start = Instant::now();
+        drop(guard);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        ::std::thread::sleep(Duration::from_millis(100));
+        assert!(!running(&pid));
+    }
+
+    #[test]
+    fn drop_policy_terminate_after_wait() {
+        let file = ::std::env::temp_dir().join(format!("commandspec-{}-background-pid", ::std::process::id()));
+        let script = format!("sleep 77 & echo $! > '{}'", file.display());
+        let guard = command!("sh -c {}", script)
+            .unwrap()
+            .scoped_spawn()
+            .unwrap()
+            .drop_policy(DropPolicy::Terminate(Duration::from_millis(200)));
+        // Waiting reaps the leader, but the `sleep` it left behind is still in its group.
+        guard.wait().unwrap();
+        let pid = fs::read_to_string(&file).unwrap().trim().to_string();
+        fs::remove_file(&file).unwrap();
+        assert!(running(&pid));
+
+        drop(guard);
+        ::std::thread::sleep(Duration::from_millis(100));
+        assert!(!running(&pid));
+    }
+
+    #[test]
+    fn drop_policy_wait() {
+        let start = Instant::now();
+        let guard = command!("sleep 0.3").unwrap().scoped_spawn().unwrap().drop_policy(DropPolicy::Wait);
+        drop(guard);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+}
+
+#[cfg(all(unix, feature = "tokio"))]
+mod async_tokio {
+    use std::future::Future;
+    use std::time::{Duration, Instant};
+    use std::sync::{Arc, Mutex};
+    use tb2f_commandspec::tokio::AsyncCommandSpecExt;
+    use tb2f_commandspec::{
+        with_dry_run, with_executor, CommandError, DropPolicy, DryRun, MockExecutor, MockResponse, Signal, Timeout,
+    };
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn async_execute() {
+        block_on(async {
+            command!("true").unwrap().execute().await.unwrap();
+            let err = command!("sh -c 'exit 3'").unwrap().execute().await.unwrap_err();
+            assert_eq!(err.error_code(), 3);
+        });
+    }
+
+    #[test]
+    fn async_output() {
+        block_on(async {
+            let stdout = command!("echo {}", "hello").unwrap().execute_stdout().await.unwrap();
+            assert_eq!(stdout, "hello\n");
+
+            let err = command!("sh -c 'echo oops >&2; exit 1'").unwrap().execute_output().await.unwrap_err();
+            assert_eq!(err.error_code(), 1);
+            match err {
+                CommandError::Output(_, ref output) => assert_eq!(output.stderr, b"oops\n".to_vec()),
+                ref other => panic!("unexpected error: {}", other),
+            }
+            assert!(err.to_string().ends_with("stderr:\noops"));
+        });
+    }
+
+    #[test]
+    fn async_spawn_guard() {
+        block_on(async {
+            let guard = command!("sleep 30").unwrap().scoped_spawn().unwrap();
+            assert!(guard.try_wait().is_none());
+            guard.signal(Signal::SIGTERM);
+            match guard.wait().await {
+                Err(CommandError::Signaled(Signal::SIGTERM, _, _)) => {}
+                Err(other) => panic!("unexpected error: {}", other),
+                Ok(()) => panic!("expected the command to be signaled"),
+            }
+        });
+    }
+
+    #[test]
+    fn async_drop_policy_terminate() {
+        block_on(async {
+            let guard = command!("sleep 30")
+                .unwrap()
+                .scoped_spawn()
+                .unwrap()
+                .drop_policy(DropPolicy::Terminate(Duration::from_millis(100)));
+            let pid = guard.id();
+            let start = Instant::now();
+            drop(guard);
+            while command!("kill -0 {}", pid.to_string()).unwrap().execute().await.is_ok() {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+    }
+
+    #[test]
+    fn async_drop_policy_wait() {
+        block_on(async {
+            let guard = command!("sleep 0.2").unwrap().scoped_spawn().unwrap().drop_policy(DropPolicy::Wait);
+            let start = Instant::now();
+            drop(guard);
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        });
+    }
+
+    #[test]
+    fn async_pipeline_and_sequence() {
+        block_on(async {
+            pipeline!("printf 'a\nb\n' | grep b").unwrap().execute_async().await.unwrap();
+            let statuses = pipeline!("true | false").unwrap().status_async().await.unwrap();
+            assert!(statuses[0].success() && !statuses[1].success());
+
+            sequence!("false || true").unwrap().execute_async().await.unwrap();
+            match sequence!("true && sh -c 'exit 4'").unwrap().execute_async().await {
+                Err(CommandError::Step(1, err)) => assert_eq!(err.error_code(), 4),
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            let pipeline = pipeline!("sleep 30").unwrap().timeout(Timeout::new(Duration::from_millis(100)));
+            match pipeline.execute_async().await {
+                Err(CommandError::Timeout(..)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn async_dry_run() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let sink = shown.clone();
+        with_dry_run(DryRun::callback(move |command| sink.lock().unwrap().push(command.to_string())), || {
+            block_on(async {
+                command!("false").unwrap().execute().await.unwrap();
+                assert_eq!(command!("echo hi").unwrap().execute_stdout().await.unwrap(), "");
+                let guard = command!("sleep 30").unwrap().scoped_spawn().unwrap();
+                assert_eq!(guard.id(), 0);
+                guard.wait().await.unwrap();
+                pipeline!("false | false").unwrap().execute_async().await.unwrap();
+            })
+        });
+        assert_eq!(shown.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn async_executor() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("git status", MockResponse::code(0).stdout("clean\n"));
+        mock.on("false", MockResponse::code(1));
+        with_executor(mock.clone(), || {
+            block_on(async {
+                assert_eq!(command!("git status").unwrap().execute_stdout().await.unwrap(), "clean\n");
+                assert_eq!(command!("false").unwrap().execute().await.unwrap_err().error_code(), 1);
+            })
+        });
+        assert_eq!(mock.command_lines(), vec!["git status", "false"]);
+    }
+}
+
+#[cfg(not(windows))]
+mod lines {
+    use tb2f_commandspec::{CommandError, CommandSpecExt, Stream};
+
+    #[test]
+    fn lines_streamed() {
+        let mut lines = vec![];
+        command!("sh -c 'echo one; sleep 0.1; echo two >&2; sleep 0.1; printf three'")
+            .unwrap()
+            .execute_lines(0, |stream, line| lines.push((stream, line.to_string())))
+            .unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Stdout, "one".to_string()),
+                (Stream::Stderr, "two".to_string()),
+                (Stream::Stdout, "three".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_failure_keeps_tail() {
+        let mut count = 0;
+        let err = command!("sh -c 'for i in 1 2 3 4; do echo line$i >&2; done; exit 2'")
+            .unwrap()
+            .execute_lines(2, |_, _| count += 1)
+            .unwrap_err();
+        assert_eq!(count, 4);
+        match err {
+            CommandError::Code(2, ref context) => assert_eq!(context.stderr_tail, Some("line3\nline4".into())),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert!(err.to_string().ends_with("stderr:\nline3\nline4"));
+    }
+}
+
+#[cfg(not(windows))]
+mod tee {
+    use std::fs;
+    use tb2f_commandspec::{CommandSpecExt, Tee};
+
+    #[test]
+    fn tee_to_sinks() {
+        let path = ::std::env::temp_dir().join(format!("commandspec-tee-{}.log", ::std::process::id()));
+        let mut out = vec![];
+        let mut err = vec![];
+        let res = command!("sh -c 'echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three; exit 1'")
+            .unwrap()
+            .execute_tee(Tee::new().both(fs::File::create(&path).unwrap()).stdout(&mut out).stderr(&mut err));
+        assert_eq!(res.unwrap_err().error_code(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n");
+        assert_eq!(out, b"one\nthree\n".to_vec());
+        assert_eq!(err, b"two\n".to_vec());
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(not(windows))]
+mod input {
+    use std::io::Cursor;
+    use std::process::Stdio;
+    use tb2f_commandspec::{CommandSpecExt, Input};
+
+    #[test]
+    fn input_from_values() {
+        command!("grep -q hello").unwrap().execute_with_input("say hello\n").unwrap();
+        let err = command!("grep -q hello").unwrap().execute_with_input(b"bye\n".to_vec()).unwrap_err();
+        assert_eq!(err.error_code(), 1);
+        command!("grep -q big").unwrap().execute_with_input(Input::reader(Cursor::new("big"))).unwrap();
+    }
+
+    #[test]
+    fn input_large_without_deadlock() {
+        // More than fits into a pipe at once.
+        let input = "x".repeat(1 << 20);
+        command!("sh -c 'cat >/dev/null; exit 0'").unwrap().execute_with_input(input.clone()).unwrap();
+        command!("sh -c 'cat | wc -c >/dev/null'").unwrap().execute_with_input(input).unwrap();
+        // A command that doesn't read its input isn't an error.
+        command!("true").unwrap().execute_with_input("x".repeat(1 << 20)).unwrap();
+    }
+
+    #[test]
+    fn input_heredoc() {
+        let name = "a 'b' $c";
+        let output = command!(
+            r"
+                cat <<EOF
+                    hello {name}
+                      indented
+                EOF
+            "
+        )
+        .unwrap()
+        .execute_stdout()
+        .unwrap();
+        assert_eq!(output, "hello a 'b' $c\n  indented\n");
+
+        execute!(
+            r"
+                grep -q needle <<END && test -n {}
+                hay
+                needle
+                END
+            ",
+            "x"
+        )
+        .unwrap();
+        assert!(execute!("cat <<EOF\nnever ends").is_err());
+    }
+
+    #[test]
+    fn heredoc_written_on_spawn() {
+        // Nothing is written until the command runs, and every run gets the whole body.
+        let mut command = command!("cat <<EOF\nbody\nEOF").unwrap();
+        let children = (0..4).map(|_| command.stdout(Stdio::piped()).spawn().unwrap()).collect::<Vec<_>>();
+        for child in children {
+            assert_eq!(child.wait_with_output().unwrap().stdout, b"body\n".to_vec());
+        }
+        assert_eq!(command.output().unwrap().stdout, b"body\n".to_vec());
+    }
+}
+
+#[cfg(not(windows))]
+mod dry_run {
+    use std::sync::{Arc, Mutex};
+    use tb2f_commandspec::{with_dry_run, CommandSpecExt, DryRun};
+
+    // Runs `f` as a dry run and returns what would have run.
+    fn recorded<F: FnOnce()>(f: F) -> Vec<String> {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        with_dry_run(DryRun::callback(move |command| sink.lock().unwrap().push(command.to_string())), f);
+        let seen = seen.lock().unwrap();
+        seen.clone()
+    }
+
+    #[test]
+    fn dry_run_execute() {
+        let seen = recorded(|| {
+            execute!("cd /\nexport MODE=fast\nfalse && touch {} | wc -l", "a b").unwrap();
+        });
+        assert_eq!(seen, vec!["cd /\nexport MODE=fast\nfalse && touch \"a b\" | wc -l"]);
+
+        let seen = recorded(|| sh_execute!("exit 1").unwrap());
+        assert_eq!(seen.len(), 1);
+        assert!(seen[0].contains("sh -c"), "{}", seen[0]);
+    }
+
+    #[test]
+    fn dry_run_leaves_redirect_targets() {
+        let dir = std::env::temp_dir().join(format!("commandspec-dry-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("kept");
+        std::fs::write(&target, "kept\n").unwrap();
+
+        let seen = recorded(|| {
+            execute!("echo hi > {} && cat <<EOF\nbody\nEOF", target).unwrap();
+            pipeline!("echo hi | cat 2>&1 >> {}", target).unwrap().execute().unwrap();
+            command!("echo hi > {}", target).unwrap().execute().unwrap();
+        });
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "kept\n");
+        let quoted = target.to_string_lossy();
+        assert!(seen[0].ends_with(&format!("echo hi > {} && cat <<'EOF'\nbody\nEOF", quoted)), "{}", seen[0]);
+        assert!(seen[1].ends_with(&format!("echo hi | cat 2>&1 >> {}", quoted)), "{}", seen[1]);
+        // A plain `Command` doesn't know about its redirects.
+        assert!(seen[2].ends_with("\necho hi"), "{}", seen[2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_scoped_spawn() {
+        let seen = recorded(|| {
+            let guard = command!("sleep 30").unwrap().scoped_spawn().unwrap();
+            assert_eq!(guard.id(), 0);
+            assert!(guard.exit_status().unwrap().success());
+            guard.wait().unwrap();
+        });
+        assert_eq!(seen.len(), 1);
+        assert!(seen[0].starts_with("cd ") && seen[0].ends_with("\nsleep 30"), "{}", seen[0]);
+        // Outside of the closure, commands run again.
+        assert!(command!("false").unwrap().execute().is_err());
+    }
+}
+
+#[cfg(not(windows))]
+mod shell_string {
+    use tb2f_commandspec::{CommandArg, CommandSpec, CommandSpecExt, Template};
+
+    #[test]
+    fn shell_string_rendering() {
+        let spec = Template::new()
+            .text("cd /\nexport B='x $y' A=1\ntimeout 1.5s\ncat ")
+            .arg(CommandArg::Literal("$HOME".into()))
+            .text(" 'a b' <in >>out 2>&1");
+        assert_eq!(
+            CommandSpec::parse(spec).unwrap().to_shell_string(),
+            "cd / && env A=1 B=\"x \\$y\" timeout 1.5s cat \"\\$HOME\" \"a b\" < in >> out 2>&1"
+        );
+        assert!(CommandSpec::parse("a | b").is_err());
+    }
+
+    #[test]
+    fn shell_string_reproduces() {
+        let spec = || {
+            Template::new()
+                .text("cd /\nexport GREETING='hi $there `x`'\n")
+                .text(r#"sh -c 'printf "%s|%s|%s\n" "$GREETING" "$1" "$(pwd)"' x "#)
+                .arg(CommandArg::Literal("a \"b\" \\c".into()))
+        };
+        let direct = tb2f_commandspec::commandify(spec()).unwrap().execute_stdout().unwrap();
+        let line = CommandSpec::parse(spec()).unwrap().to_shell_string();
+        let replayed = command!("sh -c {}", line).unwrap().execute_stdout().unwrap();
+        assert_eq!(direct, "hi $there `x`|a \"b\" \\c|/\n");
+        assert_eq!(replayed, direct);
+
+        let heredoc = CommandSpec::parse("cat <<END\nEOF_\nEOF\nEND").unwrap().to_shell_string();
+        let replayed = command!("sh -c {}", heredoc).unwrap().execute_stdout().unwrap();
+        assert_eq!(replayed, "EOF_\nEOF\n");
+
+        // A redirect after the heredoc stays on the command line.
+        let dir = std::env::temp_dir().join(format!("commandspec-shell-string-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = Template::new().text("cd ").arg(dir.as_path().into()).text("\ncat <<END > out\nbody\nEND");
+        let line = CommandSpec::parse(spec).unwrap().to_shell_string();
+        assert!(line.ends_with("cat <<'EOF' > out\nbody\nEOF"), "{}", line);
+        command!("sh -c {}", line).unwrap().execute().unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("out")).unwrap(), "body\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(not(windows))]
+mod spec {
+    use std::convert::TryFrom;
+    use std::ffi::OsStr;
+    use std::process::Command;
+    use tb2f_commandspec::{CommandError, CommandSpec, CommandSpecExt};
+
+    #[test]
+    fn spec_modify() {
+        let mut spec = CommandSpec::parse("cd /\nsh -c 'echo \"$MODE\" \"$@\"' x a").unwrap();
+        assert_eq!(spec.binary, "sh");
+        assert_eq!(spec.cd.as_ref().unwrap().to_str(), Some("/"));
+        spec.args.push("--verbose".into());
+        spec.env.insert("MODE".into(), "fast".into());
+        assert_eq!(spec.to_command().unwrap().execute_stdout().unwrap(), "fast a --verbose\n");
+    }
+
+    #[test]
+    fn spec_from_command() {
+        let mut command = Command::new("ls");
+        command.arg("-l").current_dir("/tmp").env("A", "1");
+        let spec = CommandSpec::try_from(&command).unwrap();
+        assert_eq!(spec.binary, "ls");
+        assert_eq!(spec.args, vec!["-l"]);
+        assert_eq!(spec.env[OsStr::new("A")], "1");
+        assert_eq!(spec.to_shell_string(), "cd /tmp && env A=1 ls -l");
+
+        command.env_remove("HOME");
+        match CommandSpec::try_from(&command) {
+            Err(CommandError::EnvRemoved(ref key)) => assert_eq!(key, "HOME"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spec_preamble_comments() {
+        let spec = CommandSpec::parse("# Build from the root.\ncd /\n\n# Quietly.\nexport MODE=fast\nls -l").unwrap();
+        assert_eq!(spec.cd.as_ref().unwrap().to_str(), Some("/"));
+        assert_eq!(spec.env[OsStr::new("MODE")], "fast");
+        assert_eq!(spec.binary, "ls");
+    }
+}
+
+#[cfg(all(not(windows), feature = "serde"))]
+mod serde_spec {
+    use std::time::{Duration, Instant};
+    use tb2f_commandspec::{commandify, CommandContext, CommandError, CommandSpec};
+
+    #[test]
+    fn serde_from_toml() {
+        let spec = CommandSpec::from_toml(
+            r#"
+                program = "cargo"
+                args = ["build", "--target-dir", "out dir"]
+                cwd = "/"
+                stdio = [{ stdout = "log" }, "stderr_to_stdout"]
+
+                [env]
+                RUST_LOG = "full"
+            "#,
+        )
+        .unwrap();
+        let expected = CommandSpec::parse(
+            "cd /\nexport RUST_LOG=full\ncargo build --target-dir 'out dir' >log 2>&1",
+        )
+        .unwrap();
+        assert_eq!(spec.to_shell_string(), expected.to_shell_string());
+
+        let spec = CommandSpec::from_json(r#"{"program": "ls", "args": ["-l"], "cwd": "/"}"#).unwrap();
+        assert_eq!(
+            CommandContext::new(&spec.to_command().unwrap()),
+            CommandContext::new(&commandify("cd /\nls -l").unwrap())
+        );
+        assert!(CommandSpec::from_json(r#"{"program": "ls", "cmd": "x"}"#).is_err());
+        assert!(CommandSpec::from_toml("program = \"ls\"\ntimeout = \"soon\"").is_err());
+        assert!(CommandSpec::from_toml("program = \"ls\"\nstdio = [{ stdout = \"a\", stderr = \"b\" }]").is_err());
+        assert!(CommandSpec::from_toml("program = \"ls\"\nstdio = [\"stdout\"]").is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn serde_from_yaml() {
+        let spec = CommandSpec::from_yaml("program: ls\nargs: [-l]\ncwd: /\nstdio:\n  - stdout: log\n  - stderr_to_stdout\n")
+            .unwrap();
+        assert_eq!(spec.to_shell_string(), CommandSpec::parse("cd /\nls -l >log 2>&1").unwrap().to_shell_string());
+        assert!(CommandSpec::from_yaml("program: ls\ncmd: x\n").is_err());
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let spec = CommandSpec::parse("cd /tmp\nexport A=1\ntimeout 30s\ncat <<EOF >>out\nhello\nEOF").unwrap();
+        for copy in &[
+            CommandSpec::from_json(&spec.to_json().unwrap()).unwrap(),
+            CommandSpec::from_toml(&spec.to_toml().unwrap()).unwrap(),
+        ] {
+            assert_eq!(copy.to_shell_string(), spec.to_shell_string());
+        }
+        #[cfg(feature = "yaml")]
+        assert_eq!(CommandSpec::from_yaml(&spec.to_yaml().unwrap()).unwrap().to_shell_string(), spec.to_shell_string());
+    }
+
+    #[test]
+    fn serde_stdin_text() {
+        let spec = CommandSpec::from_json(r#"{"program": "cat", "stdio": [{"stdin_text": "hello"}]}"#).unwrap();
+        assert_eq!(spec.to_shell_string(), "cat <<'EOF'\nhello\nEOF");
+    }
+
+    #[test]
+    fn serde_timeout() {
+        let spec = CommandSpec::from_toml("program = \"sleep\"\nargs = [\"5\"]\ntimeout = \"100ms\"").unwrap();
+        match spec.to_command() {
+            Err(CommandError::UnexpectedTimeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let start = Instant::now();
+        match spec.to_pipeline().unwrap().execute() {
+            Err(CommandError::Timeout(..)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
+
+#[cfg(not(windows))]
+mod spec_file {
+    use std::fs;
+    use tb2f_commandspec::{CommandError, CommandSpecExt, SpecFile};
+
+    #[test]
+    fn spec_file_params() {
+        let path = ::std::env::temp_dir().join(format!("commandspec-{}.cmdspec", ::std::process::id()));
+        fs::write(&path, "# Prints its arguments\ncd {dir}\nprintf '%s|' {{x}} {words} {name}\n").unwrap();
+        let output = SpecFile::open(&path)
+            .unwrap()
+            .param("dir", &"/")
+            .param("words", &vec!["a b", "c"])
+            .param("name", &"$HOME")
+            .command()
+            .unwrap()
+            .execute_stdout()
+            .unwrap();
+        assert_eq!(output, "{x}|a b|c|$HOME|");
+        fs::remove_file(&path).unwrap();
+
+        assert!(SpecFile::open(&path).unwrap_err().to_string().starts_with(&path.display().to_string()));
+    }
+
+    #[test]
+    fn spec_file_errors() {
+        let spec = SpecFile::new("build.cmdspec", "cd /\nexport MODE=fast\n\necho 'a |\nb\n").param("x", &"y");
+        match spec.command() {
+            Err(CommandError::SpecFile(_, Some(4), ref err)) => match **err {
+                CommandError::UnbalancedQuotes => {}
+                ref other => panic!("unexpected error: {}", other),
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let err = SpecFile::new("build.cmdspec", "echo a\n  && echo {missing}").sequence().unwrap_err();
+        assert!(err.to_string().starts_with("build.cmdspec:2: No value for the parameter {missing}"), "{}", err);
+
+        let err = SpecFile::new("x.cmdspec", "echo a\necho b | wc\ntrue && true").pipeline().unwrap_err();
+        assert!(err.to_string().starts_with("x.cmdspec:3: "), "{}", err);
+
+        SpecFile::new("x.cmdspec", "true &&\n  true").sequence().unwrap().execute().unwrap();
+    }
+
+    #[test]
+    fn spec_file_error_lines() {
+        let line = |text: &str, build: fn(&SpecFile) -> Result<(), CommandError>| match build(&SpecFile::new("x", text)) {
+            Err(CommandError::SpecFile(_, line, _)) => line,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let command = |spec: &SpecFile| spec.command().map(drop);
+        let pipeline = |spec: &SpecFile| spec.pipeline().map(drop);
+
+        // Leading blank lines still count, and the directory is blamed on `cd`.
+        assert_eq!(line("\n# build\ncd /definitely/not/here\n\nls\n", command), Some(3));
+        assert_eq!(line("echo a\n  | cat\n  | wc\ntimeout 1s", command), Some(2));
+        assert_eq!(line("timeout 1s\necho a", command), Some(1));
+        assert_eq!(line("echo a |\n  |\n  wc", pipeline), Some(2));
+        assert_eq!(line("cat <<EOF\nbody\nEOF\necho >", pipeline), Some(4));
+        assert_eq!(line("export A=1\nexport\nls", pipeline), Some(2));
+        assert_eq!(line("cd /definitely/not/here\necho a | wc", pipeline), Some(1));
+        assert_eq!(line("\n\n", pipeline), None);
+    }
+}
+
+#[cfg(not(windows))]
+mod executor {
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tb2f_commandspec::{with_executor, CommandError, CommandSpecExt, MockExecutor, MockResponse, Timeout};
+
+    #[test]
+    fn executor_mock() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("git status", MockResponse::code(0).stdout("clean\n"));
+        mock.on("docker", MockResponse::code(3).stderr("no daemon"));
+
+        with_executor(mock.clone(), || {
+            execute!("cd /tmp\nexport MODE=fast\ngit status --short").unwrap();
+            let status = command!("git status").unwrap().execute_stdout().unwrap();
+            assert_eq!(status, "clean\n");
+            match command!("docker ps -a").unwrap().execute() {
+                Err(CommandError::Code(3, _)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            // Commands without a rule succeed.
+            execute!("rm -rf {}", "/definitely/not/here").unwrap();
+        });
+
+        let commands = mock.commands();
+        assert_eq!(commands[0].program, "git");
+        assert_eq!(commands[0].args, vec![OsString::from("status"), OsString::from("--short")]);
+        assert_eq!(commands[0].cwd.as_deref(), Some(Path::new("/tmp")));
+        assert!(commands[0].env.contains(&("MODE".into(), Some("fast".into()))));
+        assert_eq!(
+            mock.command_lines(),
+            vec!["git status --short", "git status", "docker ps -a", "rm -rf /definitely/not/here"]
+        );
+        // Outside of the closure, commands run again.
+        assert!(command!("false").unwrap().execute().is_err());
+    }
+
+    #[test]
+    fn executor_pipeline() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("grep", MockResponse::code(1));
+        with_executor(mock.clone(), || {
+            assert!(execute!("echo a | grep b").is_err());
+            execute!("true && echo c").unwrap();
+        });
+        assert_eq!(mock.command_lines(), vec!["echo a", "grep b", "true", "echo c"]);
+        let stages = mock.runs().iter().map(|run| run.stages.len()).collect::<Vec<_>>();
+        assert_eq!(stages, vec![2, 1, 1]);
+    }
+
+    #[test]
+    fn executor_input() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("tr", MockResponse::code(0).stdout("A\n"));
+        with_executor(mock.clone(), || {
+            command!("tr a-z A-Z").unwrap().execute_with_input("a\n").unwrap();
+            execute!("true").unwrap();
+        });
+        let runs = mock.runs();
+        assert_eq!(runs[0].stdin.as_deref(), Some(&b"a\n"[..]));
+        assert_eq!(runs[1].stdin, None);
+    }
+
+    #[test]
+    fn executor_timeout() {
+        let mock = Arc::new(MockExecutor::new());
+        with_executor(mock.clone(), || {
+            pipeline!("timeout 30s\nsleep 60").unwrap().execute().unwrap();
+            command!("sleep 60").unwrap().execute_timeout(Timeout::new(Duration::from_secs(5))).unwrap();
+            command!("sleep 60").unwrap().scoped_spawn_timeout(Timeout::new(Duration::from_secs(1))).unwrap();
+            execute!("true").unwrap();
+        });
+        let timeouts = mock.runs().iter().map(|run| run.timeout).collect::<Vec<_>>();
+        assert_eq!(
+            timeouts,
+            vec![Some(Duration::from_secs(30)), Some(Duration::from_secs(5)), Some(Duration::from_secs(1)), None]
+        );
+    }
+}
+
+#[cfg(all(not(windows), feature = "serde"))]
+mod recording {
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tb2f_commandspec::{with_executor, CommandError, CommandSpecExt, Recorder, Replayer, Timeout};
+
+    // Runs the same commands while recording and while replaying.
+    fn script() -> (Result<(), CommandError>, String) {
+        let failed = execute!("cd /\nexport MODE=fast\nsh -c 'echo out; echo $MODE >&2; exit 2'");
+        let stdout = command!("printf {}", "a b").unwrap().execute_stdout().unwrap();
+        (failed, stdout)
+    }
+
+    #[test]
+    fn recording_record_replay() {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("commandspec-{}-recording.json", ::std::process::id()));
+
+        let recorder = Arc::new(Recorder::create(&path).unwrap());
+        let (failed, stdout) = with_executor(recorder, script);
+        match failed {
+            Err(CommandError::Code(2, _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(stdout, "a b");
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.contains("\"MODE\": \"fast\"") && json.contains("\"stderr\": \"fast\\n\""), "{}", json);
+        assert!(json.contains("\"cwd\": \"/\"") && json.contains("\"cwd\": \".\""), "{}", json);
+
+        let replayer = Arc::new(Replayer::open(&path).unwrap());
+        with_executor(replayer.clone(), || {
+            let (failed, stdout) = script();
+            match failed {
+                Err(CommandError::Code(2, _)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            assert_eq!(stdout, "a b");
+            // Each recorded run is served once, and unknown commands fail.
+            assert!(command!("printf {}", "a b").unwrap().execute().is_err());
+            match execute!("git push") {
+                Err(CommandError::Io(_, Some(_))) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        });
+        assert!(replayer.unplayed().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recording_pipeline_input() {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("commandspec-{}-recording-input.json", ::std::process::id()));
+
+        // Both only succeed if the pipe and the input really reach `grep`.
+        let script = || -> Result<(), CommandError> {
+            execute!("printf 'a\\nb\\n' | grep -q b")?;
+            command!("grep -q x").unwrap().execute_with_input("x\n")
+        };
+        with_executor(Arc::new(Recorder::create(&path).unwrap()), script).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.contains("\"stdin\": \"x\\n\""), "{}", json);
+
+        let replayer = Arc::new(Replayer::open(&path).unwrap());
+        with_executor(replayer.clone(), || {
+            // Other input doesn't match the recorded run.
+            assert!(command!("grep -q x").unwrap().execute_with_input("y\n").is_err());
+            script().unwrap();
+        });
+        assert!(replayer.unplayed().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recording_timeout() {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("commandspec-{}-recording-timeout.json", ::std::process::id()));
+
+        let script = || {
+            let start = Instant::now();
+            match execute!("timeout 200ms\nsleep 10") {
+                Err(CommandError::Timeout(..)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let guard = command!("sleep 10").unwrap().scoped_spawn_timeout(Timeout::new(Duration::from_millis(200))).unwrap();
+            match guard.wait() {
+                Err(CommandError::Timeout(..)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        };
+        with_executor(Arc::new(Recorder::create(&path).unwrap()), script);
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.contains("\"timed_out\": true"), "{}", json);
+
+        let replayer = Arc::new(Replayer::open(&path).unwrap());
+        with_executor(replayer.clone(), script);
+        assert!(replayer.unplayed().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(not(windows))]
+mod shell {
+    use tb2f_commandspec::{with_shell, CommandError, CommandSpecExt, Shell};
+
+    #[test]
+    fn shell_choice() {
+        // The default `sh` keeps going after a failing pipeline stage.
+        sh_execute!("false | true").unwrap();
+        with_shell(Shell::bash_strict(), || {
+            assert!(sh_execute!("false | true").is_err());
+            assert!(sh_execute!("echo $UNSET_COMMANDSPEC_VARIABLE").is_err());
+            let out = sh_command!("echo ${{BASH_VERSION:+bash}} {}", "a b").unwrap().execute_stdout().unwrap();
+            assert_eq!(out, "bash a b\n");
+        });
+        with_shell(Shell::dash().prelude("x=1"), || {
+            let err = sh_command!("echo $x; false; echo no").unwrap().execute_output().unwrap_err();
+            assert_eq!(err.error_code(), 1);
+            match err {
+                CommandError::Output(_, ref output) => assert_eq!(output.stdout, b"1\n"),
+                ref other => panic!("unexpected error: {}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn shell_script_via_stdin() {
+        // Longer than Linux allows for a single argument.
+        let long = "x".repeat(1024 * 1024);
+        with_shell(Shell::bash().script_via_stdin(), || {
+            let out = sh_command!("v={}\necho ${{#v}}", long).unwrap().execute_stdout().unwrap();
+            assert_eq!(out, format!("{}\n", long.len()));
+        });
+        // The script comes from a heredoc, so `set -e` and a trailing line still work.
+        with_shell(Shell::sh().script_via_stdin(), || {
+            assert_eq!(sh_command!("echo a\necho b").unwrap().execute_stdout().unwrap(), "a\nb\n");
+            assert_eq!(sh_execute!("false\necho no").unwrap_err().error_code(), 1);
+        });
+    }
+}